@@ -1,13 +1,41 @@
-use crate::{Command, Timestamp, Metric};
-use std::net::SocketAddr;
+use crate::{
+    Ack, Command, Event, Labels, Log, LogLevel, Metric, StreamMessage, Timestamp, MAX_DATAGRAM_SIZE,
+};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Client {
     project: String,
     service: String,
-    socket_addr: SocketAddr,
+    labels: Labels,
+    transport: Transport,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Udp(SocketAddr),
+    Tcp(Arc<TcpTransport>),
+}
+
+struct TcpTransport {
+    addr: SocketAddr,
+    connection: Mutex<Option<TcpConnection>>,
+}
+
+struct TcpConnection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    next_sync: u64,
 }
 
 impl Client {
+    /// Send commands as fire-and-forget UDP datagrams.
     pub fn from_socketaddr(
         project: impl ToString,
         service: impl ToString,
@@ -16,9 +44,55 @@ impl Client {
         Self {
             project: project.to_string(),
             service: service.to_string(),
-            socket_addr: addr,
+            labels: Labels::new(),
+            transport: Transport::Udp(addr),
+        }
+    }
+
+    /// Send commands over a persistent TCP connection to the server's TCP
+    /// ingest endpoint. The connection is opened on first use and reopened
+    /// if it breaks. Use [`Client::sync`] to wait for acknowledgements.
+    pub fn from_tcp_socketaddr(
+        project: impl ToString,
+        service: impl ToString,
+        addr: SocketAddr,
+    ) -> Self {
+        Self {
+            project: project.to_string(),
+            service: service.to_string(),
+            labels: Labels::new(),
+            transport: Transport::Tcp(Arc::new(TcpTransport {
+                addr,
+                connection: Mutex::new(None),
+            })),
         }
     }
+
+    /// Attach `labels` (e.g. `[("host", "web-1")]`) to every record and
+    /// metric sent by this client, and as fields to every log message.
+    pub fn with_labels(mut self, labels: impl IntoLabels) -> Self {
+        self.labels.extend(labels.into_labels());
+        self
+    }
+}
+
+/// Anything that can be turned into [`Labels`], such as an array or vector
+/// of key/value pairs.
+pub trait IntoLabels {
+    fn into_labels(self) -> Labels;
+}
+
+impl<I, K, V> IntoLabels for I
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: ToString,
+    V: ToString,
+{
+    fn into_labels(self) -> Labels {
+        self.into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
 }
 
 impl Clone for Client {
@@ -26,7 +100,8 @@ impl Clone for Client {
         Self {
             project: self.project.clone(),
             service: self.service.clone(),
-            socket_addr: self.socket_addr,
+            labels: self.labels.clone(),
+            transport: self.transport.clone(),
         }
     }
 }
@@ -35,31 +110,18 @@ impl Client {
     /// Push a metric to the Kodama server. This function is non-blocking.
     #[inline]
     pub fn metric(&self, metric: impl ToString, value: f64) {
-        let metric = metric.to_string();
-        let timestamp = Timestamp::now();
-        self.command(Command::Metric(Metric {
-            project_name: self.project.clone(),
-            service_name: self.service.clone(),
-            metric_name: metric,
-            metric_value: value,
-            metric_timestamp: timestamp,
-        }))
+        self.command(self.metric_command(metric, value, Labels::new()))
+    }
+
+    /// Push a metric with labels in addition to the client's own.
+    #[inline]
+    pub fn metric_with_labels(&self, metric: impl ToString, value: f64, labels: impl IntoLabels) {
+        self.command(self.metric_command(metric, value, labels.into_labels()))
     }
 
     #[inline]
     pub fn record(&self, record: impl ToString, group_by: impl ToString, execution_time_us: u64) {
-        let record: String = record.to_string();
-        let group_by = group_by.to_string();
-        let timestamp = Timestamp::now();
-        self.command(Command::Record(crate::Record {
-            project_name: self.project.clone(),
-            service_name: self.service.clone(),
-            record_name: record,
-            group_by,
-            timestamp,
-            execution_time_us,
-            error: 0,
-        }))
+        self.command(self.record_command(record, group_by, execution_time_us, false, Labels::new()))
     }
 
     #[inline]
@@ -70,25 +132,416 @@ impl Client {
         execution_time_us: u64,
         error: bool,
     ) {
-        let record = record.to_string();
-        let group_by = group_by.to_string();
-        let timestamp = Timestamp::now();
-        self.command(Command::Record(crate::Record {
+        self.command(self.record_command(record, group_by, execution_time_us, error, Labels::new()))
+    }
+
+    /// Push an event, e.g. `client.event("login", json!({"user": 42}))`.
+    #[inline]
+    pub fn event(&self, event: impl ToString, payload: serde_json::Value) {
+        self.command(self.event_command(event, payload))
+    }
+
+    #[inline]
+    pub fn log(&self, level: LogLevel, target: impl ToString, message: impl ToString) {
+        self.command(self.log_command(level, target, message, Labels::new()))
+    }
+
+    /// Push a log message with structured fields. The client's labels are
+    /// included as fields too.
+    #[inline]
+    pub fn log_with_fields(
+        &self,
+        level: LogLevel,
+        target: impl ToString,
+        message: impl ToString,
+        fields: impl IntoLabels,
+    ) {
+        self.command(self.log_command(level, target, message, fields.into_labels()))
+    }
+
+    /// Push a record with labels in addition to the client's own.
+    #[inline]
+    pub fn record_with_labels(
+        &self,
+        record: impl ToString,
+        group_by: impl ToString,
+        execution_time_us: u64,
+        error: bool,
+        labels: impl IntoLabels,
+    ) {
+        self.command(self.record_command(
+            record,
+            group_by,
+            execution_time_us,
+            error,
+            labels.into_labels(),
+        ))
+    }
+
+    /// Collect multiple commands and send them packed into as few datagrams
+    /// as possible with [`Batch::send`].
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            client: self,
+            commands: Vec::new(),
+        }
+    }
+
+    fn metric_command(&self, metric: impl ToString, value: f64, labels: Labels) -> Command {
+        Command::Metric(Metric {
             project_name: self.project.clone(),
             service_name: self.service.clone(),
-            record_name: record,
-            group_by,
-            timestamp,
+            metric_name: metric.to_string(),
+            metric_value: value,
+            metric_timestamp: Timestamp::now(),
+            labels: self.merge_labels(labels),
+        })
+    }
+
+    fn record_command(
+        &self,
+        record: impl ToString,
+        group_by: impl ToString,
+        execution_time_us: u64,
+        error: bool,
+        labels: Labels,
+    ) -> Command {
+        Command::Record(crate::Record {
+            project_name: self.project.clone(),
+            service_name: self.service.clone(),
+            record_name: record.to_string(),
+            group_by: group_by.to_string(),
+            timestamp: Timestamp::now(),
             execution_time_us,
             error: if error { 1 } else { 0 },
-        }))
+            labels: self.merge_labels(labels),
+        })
+    }
+
+    fn event_command(&self, event: impl ToString, payload: serde_json::Value) -> Command {
+        Command::Event(Event {
+            project_name: self.project.clone(),
+            service_name: self.service.clone(),
+            event_name: event.to_string(),
+            timestamp: Timestamp::now(),
+            payload,
+        })
+    }
+
+    fn log_command(
+        &self,
+        level: LogLevel,
+        target: impl ToString,
+        message: impl ToString,
+        fields: Labels,
+    ) -> Command {
+        Command::Log(Log {
+            project_name: self.project.clone(),
+            service_name: self.service.clone(),
+            level,
+            message: message.to_string(),
+            target: target.to_string(),
+            timestamp: Timestamp::now(),
+            fields: self.merge_labels(fields),
+        })
+    }
+
+    /// The client's labels, overridden by `labels`.
+    fn merge_labels(&self, labels: Labels) -> Labels {
+        let mut merged = self.labels.clone();
+        merged.extend(labels);
+        merged
+    }
+
+    /// Wait until the server has processed every command sent before this
+    /// call. Only supported by clients created with
+    /// [`Client::from_tcp_socketaddr`].
+    pub fn sync(&self) -> std::io::Result<Ack> {
+        let Transport::Tcp(tcp) = &self.transport else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "sync requires the tcp transport",
+            ));
+        };
+
+        let mut connection = tcp.connection.lock().expect("lock");
+        let result = tcp.sync(&mut connection);
+        if result.is_err() {
+            *connection = None;
+        }
+        result
     }
 
     #[inline]
     fn command(&self, command: Command) {
-        let udp_socket = std::net::UdpSocket::bind("0.0.0.0:0").expect("bind");
-        let addr = self.socket_addr;
         let data = serde_json::to_vec(&command).expect("serde_json::to_vec");
-        udp_socket.send_to(&data, addr).expect("send_to");
+        self.send(&data);
+    }
+
+    fn send(&self, data: &[u8]) {
+        match &self.transport {
+            Transport::Udp(addr) => {
+                if data.len() > MAX_DATAGRAM_SIZE {
+                    tracing::warn!(
+                        "kodama: dropping {} byte command, limit is {} bytes",
+                        data.len(),
+                        MAX_DATAGRAM_SIZE
+                    );
+                    return;
+                }
+
+                let udp_socket = std::net::UdpSocket::bind("0.0.0.0:0").expect("bind");
+                udp_socket.send_to(data, addr).expect("send_to");
+            }
+            Transport::Tcp(tcp) => {
+                let mut connection = tcp.connection.lock().expect("lock");
+                if let Err(err) = tcp.send(&mut connection, data) {
+                    tracing::warn!("kodama: dropping command, tcp error: {}", err);
+                    *connection = None;
+                }
+            }
+        }
+    }
+}
+
+impl TcpTransport {
+    fn connect<'a>(
+        &self,
+        connection: &'a mut Option<TcpConnection>,
+    ) -> std::io::Result<&'a mut TcpConnection> {
+        if connection.is_none() {
+            let stream = TcpStream::connect_timeout(&self.addr, TCP_TIMEOUT)?;
+            stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+            stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+            *connection = Some(TcpConnection {
+                reader: BufReader::new(stream.try_clone()?),
+                stream,
+                next_sync: 0,
+            });
+        }
+
+        Ok(connection.as_mut().unwrap())
+    }
+
+    fn send(&self, connection: &mut Option<TcpConnection>, data: &[u8]) -> std::io::Result<()> {
+        let connection = self.connect(connection)?;
+        // commands are newline-delimited, serde_json never emits a raw newline
+        connection.stream.write_all(data)?;
+        connection.stream.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn sync(&self, connection: &mut Option<TcpConnection>) -> std::io::Result<Ack> {
+        let connection = self.connect(connection)?;
+        let sync = connection.next_sync;
+        connection.next_sync += 1;
+
+        let data = serde_json::to_vec(&StreamMessage::Sync { sync })?;
+        connection.stream.write_all(&data)?;
+        connection.stream.write_all(b"\n")?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if connection.reader.read_line(&mut line)? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let ack: Ack = serde_json::from_str(&line)?;
+            if ack.ack == sync {
+                return Ok(ack);
+            }
+        }
+    }
+}
+
+pub struct Batch<'a> {
+    client: &'a Client,
+    commands: Vec<Vec<u8>>,
+}
+
+impl Batch<'_> {
+    pub fn metric(&mut self, metric: impl ToString, value: f64) -> &mut Self {
+        self.push(self.client.metric_command(metric, value, Labels::new()))
+    }
+
+    pub fn metric_with_labels(
+        &mut self,
+        metric: impl ToString,
+        value: f64,
+        labels: impl IntoLabels,
+    ) -> &mut Self {
+        self.push(
+            self.client
+                .metric_command(metric, value, labels.into_labels()),
+        )
+    }
+
+    pub fn record(
+        &mut self,
+        record: impl ToString,
+        group_by: impl ToString,
+        execution_time_us: u64,
+    ) -> &mut Self {
+        self.push(self.client.record_command(
+            record,
+            group_by,
+            execution_time_us,
+            false,
+            Labels::new(),
+        ))
+    }
+
+    pub fn record_with_error(
+        &mut self,
+        record: impl ToString,
+        group_by: impl ToString,
+        execution_time_us: u64,
+        error: bool,
+    ) -> &mut Self {
+        self.push(self.client.record_command(
+            record,
+            group_by,
+            execution_time_us,
+            error,
+            Labels::new(),
+        ))
+    }
+
+    pub fn record_with_labels(
+        &mut self,
+        record: impl ToString,
+        group_by: impl ToString,
+        execution_time_us: u64,
+        error: bool,
+        labels: impl IntoLabels,
+    ) -> &mut Self {
+        self.push(self.client.record_command(
+            record,
+            group_by,
+            execution_time_us,
+            error,
+            labels.into_labels(),
+        ))
+    }
+
+    pub fn event(&mut self, event: impl ToString, payload: serde_json::Value) -> &mut Self {
+        self.push(self.client.event_command(event, payload))
+    }
+
+    pub fn log(
+        &mut self,
+        level: LogLevel,
+        target: impl ToString,
+        message: impl ToString,
+    ) -> &mut Self {
+        self.push(
+            self.client
+                .log_command(level, target, message, Labels::new()),
+        )
+    }
+
+    pub fn log_with_fields(
+        &mut self,
+        level: LogLevel,
+        target: impl ToString,
+        message: impl ToString,
+        fields: impl IntoLabels,
+    ) -> &mut Self {
+        self.push(
+            self.client
+                .log_command(level, target, message, fields.into_labels()),
+        )
+    }
+
+    fn push(&mut self, command: Command) -> &mut Self {
+        let data = serde_json::to_vec(&command).expect("serde_json::to_vec");
+        self.commands.push(data);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Send all collected commands as `Command::Batch` messages. Over UDP each
+    /// datagram is at most [`MAX_DATAGRAM_SIZE`] bytes, over TCP the whole
+    /// batch is sent as a single message. This function is non-blocking.
+    pub fn send(self) {
+        let max_size = match self.client.transport {
+            Transport::Udp(_) => MAX_DATAGRAM_SIZE,
+            Transport::Tcp(_) => usize::MAX,
+        };
+
+        for data in pack_batch(self.commands, max_size) {
+            self.client.send(&data);
+        }
+    }
+}
+
+/// Pack serialized commands into `{"Batch":[...]}` envelopes no larger than
+/// `max_size`. A command that does not fit into an envelope on its own is
+/// returned as is.
+fn pack_batch(commands: Vec<Vec<u8>>, max_size: usize) -> Vec<Vec<u8>> {
+    const HEAD: &[u8] = b"{\"Batch\":[";
+    const TAIL: &[u8] = b"]}";
+
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    for command in commands {
+        if HEAD.len() + command.len() + TAIL.len() > max_size {
+            packets.push(command);
+            continue;
+        }
+
+        if !packet.is_empty() && packet.len() + 1 + command.len() + TAIL.len() > max_size {
+            packet.extend_from_slice(TAIL);
+            packets.push(std::mem::take(&mut packet));
+        }
+
+        if packet.is_empty() {
+            packet.extend_from_slice(HEAD);
+        } else {
+            packet.push(b',');
+        }
+        packet.extend_from_slice(&command);
+    }
+
+    if !packet.is_empty() {
+        packet.extend_from_slice(TAIL);
+        packets.push(packet);
+    }
+
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_packing() {
+        let client = Client::from_socketaddr("project", "service", ([127, 0, 0, 1], 0).into());
+        let mut batch = client.batch();
+        for i in 0..20 {
+            batch.record("record", "group_by", i);
+        }
+
+        let packets = pack_batch(batch.commands, 1024);
+        assert!(packets.len() > 1);
+
+        let mut count = 0;
+        for packet in packets {
+            assert!(packet.len() <= 1024);
+            match serde_json::from_slice::<Command>(&packet).unwrap() {
+                Command::Batch(commands) => count += commands.len(),
+                _ => panic!("expected batch"),
+            }
+        }
+        assert_eq!(count, 20);
     }
 }
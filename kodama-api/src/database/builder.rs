@@ -1,5 +1,5 @@
 use crate::Result;
-use crate::{Client, DatabaseConnection};
+use crate::{Client, DatabaseConnection, Migrations};
 use std::{
     net::SocketAddr,
     path::{Path, PathBuf},
@@ -8,7 +8,7 @@ use std::{
 pub struct DatabaseBuilder {
     path: PathBuf,
     kodama: Option<(String, String, SocketAddr)>,
-    migrations: Vec<(String, String)>,
+    migrations: Migrations,
 }
 
 impl DatabaseBuilder {
@@ -16,7 +16,7 @@ impl DatabaseBuilder {
         Self {
             path: path.as_ref().to_owned(),
             kodama: None,
-            migrations: Vec::new(),
+            migrations: Migrations::new(),
         }
     }
 
@@ -31,30 +31,24 @@ impl DatabaseBuilder {
     }
 
     pub fn with_migration(mut self, version: impl Into<String>, sql: impl Into<String>) -> Self {
-        self.migrations.push((version.into(), sql.into()));
+        self.migrations = self.migrations.with_migration(version, sql);
         self
     }
 
     pub fn build(self) -> Result<Database> {
-        let conn = rusqlite::Connection::open(&self.path)?;
+        let mut conn = rusqlite::Connection::open(&self.path)?;
         // enable foreign key constraints
         conn.execute_batch("PRAGMA foreign_keys = ON;")?;
 
-        // create migrations table
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS migrations (
-                    version TEXT PRIMARY KEY
-                );",
-        )?;
+        self.migrations.apply(&mut conn)?;
 
-        let mut db = Database {
+        let db = Database {
             conn,
             kodama: self
                 .kodama
                 .map(|(name, service, addr)| Client::from_socketaddr(name, service, addr)),
         };
 
-        db.apply_migrations(&self.migrations)?;
         Ok(db)
     }
 }
@@ -65,33 +59,6 @@ pub struct Database {
 }
 
 impl Database {
-    fn has_migration(&self, version: &str) -> Result<bool> {
-        let result = self.conn.query_row(
-            "SELECT EXISTS(SELECT 1 FROM migrations WHERE version = ?1);",
-            [version],
-            |row| row.get(0),
-        )?;
-        Ok(result)
-    }
-
-    fn apply_migration(&mut self, version: &str, sql: &str) -> Result<()> {
-        let tx = self.conn.transaction()?;
-        tx.execute_batch(sql)?;
-        tx.execute("INSERT INTO migrations (version) VALUES (?1)", [version])?;
-        tx.commit()?;
-        Ok(())
-    }
-
-    fn apply_migrations(&mut self, migrations: &[(String, String)]) -> Result<()> {
-        for (version, sql) in migrations {
-            if !self.has_migration(version)? {
-                tracing::debug!("migration[{}]: apply", version);
-                self.apply_migration(version, sql)?;
-            }
-        }
-        Ok(())
-    }
-
     pub fn transaction(&self) -> Result<Transaction<'_>> {
         Transaction::new(self)
     }
@@ -0,0 +1,78 @@
+use crate::Result;
+
+/// Ordered list of versioned SQL migrations. Applied versions are tracked in
+/// a `migrations` table so every migration runs exactly once per database.
+#[derive(Clone, Debug, Default)]
+pub struct Migrations {
+    migrations: Vec<(String, String)>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_migration(mut self, version: impl Into<String>, sql: impl Into<String>) -> Self {
+        self.migrations.push((version.into(), sql.into()));
+        self
+    }
+
+    pub fn versions(&self) -> impl Iterator<Item = &str> {
+        self.migrations.iter().map(|(version, _)| version.as_str())
+    }
+
+    /// Versions that have not been applied to `conn` yet, in order.
+    pub fn pending(&self, conn: &rusqlite::Connection) -> Result<Vec<String>> {
+        let has_table: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'migrations');",
+            [],
+            |row| row.get(0),
+        )?;
+
+        let mut pending = Vec::new();
+        for (version, _) in &self.migrations {
+            if !has_table || !has_migration(conn, version)? {
+                pending.push(version.clone());
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Apply all pending migrations to `conn`, each in its own transaction,
+    /// and return the versions that were applied.
+    pub fn apply(&self, conn: &mut rusqlite::Connection) -> Result<Vec<String>> {
+        // create migrations table
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS migrations (
+                    version TEXT PRIMARY KEY
+                );",
+        )?;
+
+        let mut applied = Vec::new();
+        for (version, sql) in &self.migrations {
+            if !has_migration(conn, version)? {
+                tracing::debug!("migration[{}]: apply", version);
+                apply_migration(conn, version, sql)?;
+                applied.push(version.clone());
+            }
+        }
+        Ok(applied)
+    }
+}
+
+fn has_migration(conn: &rusqlite::Connection, version: &str) -> Result<bool> {
+    let result = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM migrations WHERE version = ?1);",
+        [version],
+        |row| row.get(0),
+    )?;
+    Ok(result)
+}
+
+fn apply_migration(conn: &mut rusqlite::Connection, version: &str, sql: &str) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(sql)?;
+    tx.execute("INSERT INTO migrations (version) VALUES (?1)", [version])?;
+    tx.commit()?;
+    Ok(())
+}
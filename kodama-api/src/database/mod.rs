@@ -1,9 +1,11 @@
 mod connection;
 mod builder;
 mod from_row;
+mod migration;
 mod query;
 
 pub use connection::*;
 pub use builder::*;
 pub use from_row::*;
+pub use migration::*;
 pub use query::*;
@@ -28,18 +28,52 @@ impl std::error::Error for Error {}
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct ErrorResponse {
     pub code: u16,
     pub message: String,
 }
 
+/// Largest datagram the Kodama server accepts, the maximum UDP payload over IPv4.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub enum Command {
     Metric(Metric),
     Record(Record),
+    Log(Log),
+    Event(Event),
+    /// Multiple commands sent in a single datagram
+    Batch(Vec<Command>),
+}
+
+/// Newline-delimited message accepted by the TCP ingest endpoint.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum StreamMessage {
+    Command(Command),
+    /// Ask the server to acknowledge every command sent before this message
+    Sync {
+        sync: u64,
+    },
+}
+
+/// Response to `StreamMessage::Sync`, sent once all preceding commands on the
+/// connection have been processed.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Ack {
+    /// Value of the acknowledged `StreamMessage::Sync`
+    pub ack: u64,
+    /// Number of commands processed on this connection so far
+    pub processed: u64,
+    /// Number of commands that failed on this connection so far
+    pub failed: u64,
 }
 
+/// Key/value labels attached to records and metrics, e.g. `host=web-1` or
+/// `region=eu`. Keys may contain ASCII letters, digits, `_`, `-` and `.`.
+pub type Labels = std::collections::BTreeMap<String, String>;
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Record {
     pub project_name: String,
@@ -50,6 +84,8 @@ pub struct Record {
     pub timestamp: Option<Timestamp>,
     pub execution_time_us: u64,
     pub error: i64, // if >0 then error
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -60,8 +96,104 @@ pub struct Metric {
 
     pub metric_timestamp: Option<Timestamp>,
     pub metric_value: f64,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
 }
 
 mod client;
 pub use client::*;
 pub use rusqlite::params;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trace => "trace",
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Warn => "warn",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "trace" => Ok(Self::Trace),
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warn" | "warning" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(format!("invalid log level: {}", value)),
+        }
+    }
+}
+
+/// Stored as an integer from 1 (trace) to 5 (error), so levels can be
+/// compared in queries.
+impl rusqlite::ToSql for LogLevel {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(rusqlite::types::ToSqlOutput::from(*self as i64 + 1))
+    }
+}
+
+impl rusqlite::types::FromSql for LogLevel {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_i64()? {
+            1 => Ok(Self::Trace),
+            2 => Ok(Self::Debug),
+            3 => Ok(Self::Info),
+            4 => Ok(Self::Warn),
+            5 => Ok(Self::Error),
+            level => Err(rusqlite::types::FromSqlError::OutOfRange(level)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Log {
+    pub project_name: String,
+    pub service_name: String,
+
+    pub level: LogLevel,
+    pub message: String,
+    /// Module or component the message originates from
+    #[serde(default)]
+    pub target: String,
+    pub timestamp: Option<Timestamp>,
+    /// Structured key/value fields of the message
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub fields: Labels,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Event {
+    pub project_name: String,
+    pub service_name: String,
+    pub event_name: String,
+
+    pub timestamp: Option<Timestamp>,
+    /// Structured data of the event, e.g. `{"user": {"country": "SE"}}`
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub payload: serde_json::Value,
+}
@@ -1,6 +1,10 @@
 use std::net::SocketAddr;
 
 use clap::Parser;
+use kodama_api::{Labels, LogLevel, Timestamp};
+use kodama_internal::alert::{self, Aggregation, AlertTarget, Comparison};
+use kodama_internal::anomaly::{self, Baseline};
+use kodama_internal::notifier::{self, NotifierKind};
 use kodama_internal::Kodama;
 
 #[derive(Parser)]
@@ -33,6 +37,187 @@ enum SubCommand {
         #[clap(subcommand)]
         subcommand: RecordSubCommand,
     },
+    #[clap(name = "alert")]
+    Alert {
+        #[clap(subcommand)]
+        subcommand: AlertSubCommand,
+    },
+    #[clap(name = "notifier")]
+    Notifier {
+        #[clap(subcommand)]
+        subcommand: NotifierSubCommand,
+    },
+    #[clap(name = "event")]
+    Event {
+        #[clap(subcommand)]
+        subcommand: EventSubCommand,
+    },
+    #[clap(name = "log")]
+    Log {
+        #[clap(subcommand)]
+        subcommand: LogSubCommand,
+    },
+    #[clap(name = "retention")]
+    Retention {
+        #[clap(subcommand)]
+        subcommand: RetentionSubCommand,
+    },
+    #[clap(name = "db")]
+    Db {
+        #[clap(subcommand)]
+        subcommand: DbSubCommand,
+    },
+}
+
+#[derive(Parser)]
+enum AlertSubCommand {
+    /// Fire while an aggregation of a record or metric compares to a threshold
+    #[clap(name = "create")]
+    #[clap(group(clap::ArgGroup::new("target").required(true)))]
+    Create {
+        project: String,
+        service: String,
+        name: String,
+        #[clap(long, group = "target")]
+        record: Option<String>,
+        #[clap(long, group = "target")]
+        metric: Option<String>,
+        /// count, sum, avg, min, max, p50, p90, p95, p99, errors, error_rate or last
+        #[clap(long)]
+        aggregation: Aggregation,
+        /// Evaluation window (e.g. `5m` or `1h`)
+        #[clap(long, default_value = "5m", value_parser = parse_duration)]
+        window: u64,
+        /// gt, ge, lt or le
+        #[clap(long, default_value = "gt")]
+        comparison: Comparison,
+        /// Execution times are in microseconds, error rates in percent
+        #[clap(long)]
+        threshold: f64,
+    },
+    #[clap(name = "list", alias = "ls")]
+    List { project: Option<String> },
+    #[clap(name = "history")]
+    History {
+        project: String,
+        #[clap(long)]
+        service: Option<String>,
+        #[clap(long)]
+        alert: Option<String>,
+        #[clap(long, default_value_t = alert::DEFAULT_HISTORY_LIMIT)]
+        limit: usize,
+    },
+}
+
+#[derive(Parser)]
+enum NotifierSubCommand {
+    /// Deliver alert notifications to a webhook, a command or a file
+    #[clap(name = "create")]
+    #[clap(group(clap::ArgGroup::new("kind").required(true)))]
+    Create {
+        name: String,
+        /// POST notifications as JSON to this url (e.g. `http://localhost:9000/alerts`)
+        #[clap(long, group = "kind")]
+        webhook: Option<String>,
+        /// Run this shell command with the notification in `KODAMA_ALERT_*` variables
+        #[clap(long, group = "kind")]
+        command: Option<String>,
+        /// Append notifications as JSON lines to this file
+        #[clap(long, group = "kind")]
+        file: Option<String>,
+    },
+    #[clap(name = "list", alias = "ls")]
+    List,
+    /// Send the changes of an alert to a notifier
+    #[clap(name = "route")]
+    Route {
+        project: String,
+        service: String,
+        alert: String,
+        notifier: String,
+        /// Repeat the notification while the alert keeps firing (e.g. `1h`)
+        #[clap(long, value_parser = parse_duration)]
+        repeat: Option<u64>,
+    },
+    #[clap(name = "routes")]
+    Routes { project: Option<String> },
+}
+
+#[derive(Parser)]
+enum EventSubCommand {
+    #[clap(name = "list", alias = "ls")]
+    List { project: String, service: String },
+    /// Count events over time, or per value of a payload field with `--by`
+    #[clap(name = "count")]
+    Count {
+        project: String,
+        service: String,
+        event: String,
+        /// Bucket width (e.g. `1m`, `1h` or `1d`)
+        #[clap(long, default_value = "1h", value_parser = parse_duration)]
+        bucket: u64,
+        /// Payload field to break the count down by (e.g. `user.country`)
+        #[clap(long, conflicts_with = "bucket")]
+        by: Option<String>,
+        /// Only include events since this time (e.g. `1h`, `7d` or `2023-12-24T18:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        since: Option<Timestamp>,
+        /// Only include events until this time (e.g. `30m` or `2023-12-25T00:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        until: Option<Timestamp>,
+    },
+}
+
+#[derive(Parser)]
+enum LogSubCommand {
+    /// Show log entries, newest first
+    #[clap(name = "search")]
+    Search {
+        project: String,
+        service: String,
+        /// Only include entries whose message contains all of these words
+        text: Option<String>,
+        /// Only include entries at or above this level (e.g. `warn`)
+        #[clap(long)]
+        level: Option<LogLevel>,
+        /// Only include entries since this time (e.g. `1h`, `7d` or `2023-12-24T18:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        since: Option<Timestamp>,
+        /// Only include entries until this time (e.g. `30m` or `2023-12-25T00:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        until: Option<Timestamp>,
+        #[clap(long, default_value_t = kodama_internal::log::DEFAULT_LIMIT)]
+        limit: usize,
+    },
+}
+
+#[derive(Parser)]
+enum RetentionSubCommand {
+    /// Set how long samples are kept for a project, service or record
+    #[clap(name = "set")]
+    Set {
+        project: String,
+        #[clap(long)]
+        service: Option<String>,
+        #[clap(long, requires = "service")]
+        record: Option<String>,
+        /// How long to keep samples (e.g. `7d` or `12h`), `none` removes the policy
+        #[clap(long, value_parser = parse_keep)]
+        keep: Keep,
+    },
+    #[clap(name = "show")]
+    Show { project: Option<String> },
+}
+
+#[derive(Clone, Copy)]
+struct Keep(Option<u64>);
+
+#[derive(Parser)]
+enum DbSubCommand {
+    #[clap(name = "migrate")]
+    Migrate,
+    #[clap(name = "status")]
+    Status,
 }
 
 #[derive(Parser)]
@@ -41,6 +226,14 @@ enum ProjectSubCommand {
     Create { name: String, description: String },
     #[clap(name = "list", alias = "ls")]
     List,
+    #[clap(name = "rename")]
+    Rename { name: String, new_name: String },
+    /// Change the description of a project
+    #[clap(name = "describe")]
+    Describe { name: String, description: String },
+    /// Delete a project with all of its services and their data
+    #[clap(name = "delete")]
+    Delete { name: String },
 }
 
 #[derive(Parser)]
@@ -53,16 +246,46 @@ enum ServiceSubCommand {
     },
     #[clap(name = "list", alias = "ls")]
     List { project: String },
+    #[clap(name = "rename")]
+    Rename {
+        project: String,
+        name: String,
+        new_name: String,
+    },
+    /// Change the description of a service
+    #[clap(name = "describe")]
+    Describe {
+        project: String,
+        name: String,
+        description: String,
+    },
+    /// Delete a service with its records, metrics, alerts and database file
+    #[clap(name = "delete")]
+    Delete { project: String, name: String },
 }
 
 #[derive(Parser)]
 enum MetricSubCommand {
+    #[clap(name = "list", alias = "ls")]
+    List { project: String, service: String },
+    #[clap(name = "data")]
+    Data {
+        project: String,
+        service: String,
+        metric: String,
+        /// Only include values with this label (e.g. `host=web-1`), may be repeated
+        #[clap(long = "label", value_parser = parse_label)]
+        labels: Vec<(String, String)>,
+    },
     #[clap(name = "push")]
     Push {
         project: String,
         service: String,
         metric: String,
         value: f64,
+        /// Label to push the value with (e.g. `host=web-1`), may be repeated
+        #[clap(long = "label", value_parser = parse_label)]
+        labels: Vec<(String, String)>,
     },
 }
 
@@ -75,6 +298,84 @@ enum RecordSubCommand {
         project: String,
         service: String,
         record: String,
+        /// Only include records since this time (e.g. `1h`, `7d` or `2023-12-24T18:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        since: Option<Timestamp>,
+        /// Only include records until this time (e.g. `30m` or `2023-12-25T00:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        until: Option<Timestamp>,
+        /// Percentiles to show instead of p50 and p95 (e.g. `90,99,99.9`)
+        #[clap(long, value_delimiter = ',')]
+        percentiles: Vec<f64>,
+        /// Only include records with this label (e.g. `host=web-1`), may be repeated
+        #[clap(long = "label", value_parser = parse_label)]
+        labels: Vec<(String, String)>,
+        /// Group by the value of this label instead of the group by value
+        #[clap(long)]
+        group_by_label: Option<String>,
+    },
+    #[clap(name = "series")]
+    Series {
+        project: String,
+        service: String,
+        record: String,
+        /// Bucket width (e.g. `1m`, `1h` or `1d`)
+        #[clap(long, default_value = "1h", value_parser = parse_duration)]
+        bucket: u64,
+        /// Only include records with this group by value
+        #[clap(long)]
+        group_by: Option<String>,
+        /// Only include records since this time (e.g. `1h`, `7d` or `2023-12-24T18:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        since: Option<Timestamp>,
+        /// Only include records until this time (e.g. `30m` or `2023-12-25T00:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        until: Option<Timestamp>,
+        /// Only include records with this label (e.g. `host=web-1`), may be repeated
+        #[clap(long = "label", value_parser = parse_label)]
+        labels: Vec<(String, String)>,
+    },
+    /// Show group by values whose p95 or error rate regressed compared to a baseline
+    #[clap(name = "anomalies")]
+    Anomalies {
+        project: String,
+        service: String,
+        record: String,
+        /// Width of the compared windows (e.g. `15m` or `1h`)
+        #[clap(long, default_value = "1h", value_parser = parse_duration)]
+        window: u64,
+        /// `rolling` compares to the preceding windows, `weekly` to the same window in previous weeks
+        #[clap(long, default_value = "rolling")]
+        baseline: Baseline,
+        /// Number of baseline windows, 24 rolling or 4 weekly by default
+        #[clap(long)]
+        windows: Option<usize>,
+        /// Z-score above which a window is flagged
+        #[clap(long, default_value_t = anomaly::DEFAULT_SENSITIVITY)]
+        sensitivity: f64,
+        /// End of the current window (e.g. `1h` or `2023-12-25T00:00:00Z`), now by default
+        #[clap(long, value_parser = parse_time)]
+        until: Option<Timestamp>,
+    },
+    /// Delete a record with all of its data, or only the data of one group by value
+    #[clap(name = "delete")]
+    Delete {
+        project: String,
+        service: String,
+        record: String,
+        /// Only delete the records with this group by value
+        #[clap(long)]
+        group_by: Option<String>,
+    },
+    /// Delete the data of a record, keeping the record itself
+    #[clap(name = "truncate")]
+    Truncate {
+        project: String,
+        service: String,
+        record: String,
+        /// Only delete records before this time (e.g. `30d` or `2023-12-24T18:00:00Z`)
+        #[clap(long, value_parser = parse_time)]
+        before: Option<Timestamp>,
     },
 }
 
@@ -102,10 +403,399 @@ fn main() {
         SubCommand::Service { subcommand } => service(instance, subcommand),
         SubCommand::Metric { subcommand } => matric(instance, subcommand),
         SubCommand::Record { subcommand } => record(instance, subcommand),
+        SubCommand::Alert { subcommand } => alert(instance, subcommand),
+        SubCommand::Notifier { subcommand } => notifier(instance, subcommand),
+        SubCommand::Event { subcommand } => event(instance, subcommand),
+        SubCommand::Log { subcommand } => log(instance, subcommand),
+        SubCommand::Retention { subcommand } => retention(instance, subcommand),
+        SubCommand::Db { subcommand } => db(instance, subcommand),
+    }
+}
+
+fn alert(kodama: Kodama, subcommand: AlertSubCommand) {
+    match subcommand {
+        AlertSubCommand::Create {
+            project,
+            service,
+            name,
+            record,
+            metric,
+            aggregation,
+            window,
+            comparison,
+            threshold,
+        } => {
+            tracing::debug!("creating alert: {:?}", name);
+            let (target, target_name) = match (record, metric) {
+                (Some(record), _) => (AlertTarget::Record, record),
+                (None, Some(metric)) => (AlertTarget::Metric, metric),
+                (None, None) => unreachable!("clap requires a target"),
+            };
+            kodama
+                .create_alert(&alert::CreateRequest {
+                    project_name: project,
+                    service_name: service,
+                    alert_name: name,
+                    target,
+                    target_name,
+                    aggregation,
+                    window_us: window,
+                    comparison,
+                    threshold,
+                })
+                .expect("create alert");
+        }
+        AlertSubCommand::List { project } => {
+            let alerts = kodama.alert_list(project.as_deref()).expect("alert list");
+
+            println!();
+            println!(
+                "{: <20} {: <20} {: <20} {: <50} {: <8} {: <7} {: <40}",
+                "[project]", "[service]", "[alert]", "[rule]", "[state]", "[value]", "[evaluated]"
+            );
+            for alert in &alerts {
+                let rule = format!(
+                    "{} {} {} over {} {} {}",
+                    alert.target,
+                    alert.target_name,
+                    alert.aggregation,
+                    duration_to_human(alert.window_us),
+                    alert.comparison,
+                    alert.threshold
+                );
+                println!(
+                    "{: <20} {: <20} {: <20} {: <50} {: <8} {: <7} {: <40}",
+                    alert.project_name,
+                    alert.service_name,
+                    alert.alert_name,
+                    rule,
+                    alert.state,
+                    alert
+                        .value
+                        .map(|value| value.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    alert
+                        .evaluated_at
+                        .as_ref()
+                        .map(timestamp_to_human)
+                        .unwrap_or_else(|| "-".to_string()),
+                );
+            }
+        }
+        AlertSubCommand::History {
+            project,
+            service,
+            alert,
+            limit,
+        } => {
+            let entries = kodama
+                .alert_history(&project, service.as_deref(), alert.as_deref(), limit)
+                .expect("alert history");
+
+            println!();
+            println!(
+                "{: <32} {: <20} {: <20} {: <10} {: >12}",
+                "[time]", "[service]", "[alert]", "[change]", "[value]"
+            );
+            for entry in &entries {
+                println!(
+                    "{: <32} {: <20} {: <20} {: <10} {: >12}",
+                    timestamp_to_human(&entry.timestamp),
+                    entry.service_name,
+                    entry.alert_name,
+                    entry.change,
+                    entry.value
+                );
+            }
+        }
+    }
+}
+
+fn notifier(kodama: Kodama, subcommand: NotifierSubCommand) {
+    match subcommand {
+        NotifierSubCommand::Create {
+            name,
+            webhook,
+            command,
+            file,
+        } => {
+            tracing::debug!("creating notifier: {:?}", name);
+            let (kind, target) = match (webhook, command, file) {
+                (Some(url), _, _) => (NotifierKind::Webhook, url),
+                (None, Some(command), _) => (NotifierKind::Command, command),
+                (None, None, Some(path)) => (NotifierKind::File, path),
+                (None, None, None) => unreachable!("clap requires a kind"),
+            };
+            kodama
+                .create_notifier(&notifier::CreateRequest {
+                    notifier_name: name,
+                    kind,
+                    target,
+                })
+                .expect("create notifier");
+        }
+        NotifierSubCommand::List => {
+            let notifiers = kodama.notifier_list().expect("notifier list");
+
+            println!();
+            println!("{: <20} {: <8} {: <60}", "[name]", "[kind]", "[target]");
+            for notifier in &notifiers {
+                println!(
+                    "{: <20} {: <8} {: <60}",
+                    notifier.name, notifier.kind, notifier.target
+                );
+            }
+        }
+        NotifierSubCommand::Route {
+            project,
+            service,
+            alert,
+            notifier,
+            repeat,
+        } => {
+            tracing::debug!("routing alert {:?} to {:?}", alert, notifier);
+            kodama
+                .route_alert(&notifier::RouteRequest {
+                    project_name: project,
+                    service_name: service,
+                    alert_name: alert,
+                    notifier_name: notifier,
+                    repeat_us: repeat,
+                })
+                .expect("route alert");
+        }
+        NotifierSubCommand::Routes { project } => {
+            let routes = kodama.route_list(project.as_deref()).expect("route list");
+
+            println!();
+            println!(
+                "{: <20} {: <20} {: <20} {: <20} {: <8} {: <10} {: <32}",
+                "[project]", "[service]", "[alert]", "[notifier]", "[repeat]", "[notified]", "[at]"
+            );
+            for route in &routes {
+                println!(
+                    "{: <20} {: <20} {: <20} {: <20} {: <8} {: <10} {: <32}",
+                    route.project_name,
+                    route.service_name,
+                    route.alert_name,
+                    route.notifier_name,
+                    route
+                        .repeat_us
+                        .map(duration_to_human)
+                        .unwrap_or_else(|| "-".to_string()),
+                    route
+                        .notified_change
+                        .map(|change| change.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    route
+                        .notified_at
+                        .as_ref()
+                        .map(timestamp_to_human)
+                        .unwrap_or_else(|| "-".to_string()),
+                );
+            }
+        }
+    }
+}
+
+fn event(mut kodama: Kodama, subcommand: EventSubCommand) {
+    match subcommand {
+        EventSubCommand::List { project, service } => {
+            let events = kodama.event_list(&project, &service).expect("event list");
+
+            println!();
+            println!("{: <40} {: >10} {: <32}", "[name]", "[count]", "[last]");
+            for event in &events {
+                println!(
+                    "{: <40} {: >10} {: <32}",
+                    event.name,
+                    event.count,
+                    timestamp_to_human(&event.last)
+                );
+            }
+        }
+        EventSubCommand::Count {
+            project,
+            service,
+            event,
+            bucket: _,
+            by: Some(field),
+            since,
+            until,
+        } => {
+            let entries = kodama
+                .event_breakdown(
+                    &project,
+                    &service,
+                    &event,
+                    &field,
+                    since.as_ref(),
+                    until.as_ref(),
+                )
+                .expect("event breakdown");
+
+            println!();
+            println!("{: <40} {: >10}", format!("[{}]", field), "[count]");
+            for entry in &entries {
+                println!("{: <40} {: >10}", entry.value, entry.count);
+            }
+        }
+        EventSubCommand::Count {
+            project,
+            service,
+            event,
+            bucket,
+            by: None,
+            since,
+            until,
+        } => {
+            let entries = kodama
+                .event_counts(
+                    &project,
+                    &service,
+                    &event,
+                    bucket,
+                    since.as_ref(),
+                    until.as_ref(),
+                )
+                .expect("event counts");
+
+            println!();
+            println!("{: <32} {: >10}", "[time]", "[count]");
+            for entry in &entries {
+                println!(
+                    "{: <32} {: >10}",
+                    timestamp_to_human(&entry.timestamp),
+                    entry.count
+                );
+            }
+        }
+    }
+}
+
+fn log(mut kodama: Kodama, subcommand: LogSubCommand) {
+    match subcommand {
+        LogSubCommand::Search {
+            project,
+            service,
+            text,
+            level,
+            since,
+            until,
+            limit,
+        } => {
+            let entries = kodama
+                .log_search(
+                    &project,
+                    &service,
+                    level,
+                    since.as_ref(),
+                    until.as_ref(),
+                    text.as_deref(),
+                    limit,
+                )
+                .expect("log search");
+
+            println!();
+            println!(
+                "{: <32} {: <7} {: <20} {: <80}",
+                "[timestamp]", "[level]", "[target]", "[message]"
+            );
+            for entry in &entries {
+                let mut message = entry.message.clone();
+                if !entry.fields.is_empty() {
+                    message.push_str(&format!(" ({})", labels_to_human(&entry.fields)));
+                }
+                println!(
+                    "{: <32} {: <7} {: <20} {: <80}",
+                    timestamp_to_human(&entry.timestamp),
+                    entry.level,
+                    entry.target,
+                    message
+                );
+            }
+        }
+    }
+}
+
+fn retention(kodama: Kodama, subcommand: RetentionSubCommand) {
+    match subcommand {
+        RetentionSubCommand::Set {
+            project,
+            service,
+            record,
+            keep,
+        } => {
+            tracing::debug!(
+                "setting retention: {:?} {:?} {:?}",
+                project,
+                service,
+                record
+            );
+            kodama
+                .set_retention(&project, service.as_deref(), record.as_deref(), keep.0)
+                .expect("set retention");
+        }
+        RetentionSubCommand::Show { project } => {
+            let policies = kodama
+                .retention_list(project.as_deref())
+                .expect("retention list");
+
+            println!();
+            println!(
+                "{: <20} {: <20} {: <40} {: >10}",
+                "[project]", "[service]", "[record]", "[keep]"
+            );
+            for policy in &policies {
+                println!(
+                    "{: <20} {: <20} {: <40} {: >10}",
+                    policy.project_name,
+                    policy.service_name.as_deref().unwrap_or("*"),
+                    policy.record_name.as_deref().unwrap_or("*"),
+                    duration_to_human(policy.keep_us),
+                );
+            }
+        }
+    }
+}
+
+fn db(mut kodama: Kodama, subcommand: DbSubCommand) {
+    match subcommand {
+        DbSubCommand::Migrate => {
+            tracing::debug!("migrating databases");
+            let applied = kodama.migrate().expect("migrate");
+
+            println!();
+            println!("applied {} migrations", applied.len());
+            for migration in &applied {
+                println!("{: <20} {: <40}", migration.database, migration.version);
+            }
+        }
+        DbSubCommand::Status => {
+            let status = kodama.migration_status().expect("migration status");
+
+            println!();
+            println!(
+                "{: <20} {: <40} {: <10}",
+                "[database]", "[version]", "[status]"
+            );
+            for migration in &status {
+                println!(
+                    "{: <20} {: <40} {: <10}",
+                    migration.database,
+                    migration.version,
+                    if migration.applied {
+                        "applied"
+                    } else {
+                        "pending"
+                    }
+                );
+            }
+        }
     }
 }
 
-fn project(kodama: Kodama, subcommand: ProjectSubCommand) {
+fn project(mut kodama: Kodama, subcommand: ProjectSubCommand) {
     match subcommand {
         ProjectSubCommand::Create { name, description } => {
             tracing::debug!("creating project: {:?}", name);
@@ -119,15 +809,33 @@ fn project(kodama: Kodama, subcommand: ProjectSubCommand) {
 
             println!();
             println!("projects:");
-            println!("{: >10} {: <80}", "[id]", "[name]");
+            println!("{: >10} {: <40} {: <80}", "[id]", "[name]", "[description]");
             for project in &projects {
-                println!("{: >10} {: <80}", project.id, project.name);
+                println!(
+                    "{: >10} {: <40} {: <80}",
+                    project.id, project.name, project.description
+                );
             }
         }
+        ProjectSubCommand::Rename { name, new_name } => {
+            tracing::debug!("renaming project: {:?} to {:?}", name, new_name);
+            kodama
+                .rename_project(&name, &new_name)
+                .expect("rename project");
+        }
+        ProjectSubCommand::Describe { name, description } => {
+            kodama
+                .set_project_description(&name, &description)
+                .expect("describe project");
+        }
+        ProjectSubCommand::Delete { name } => {
+            tracing::debug!("deleting project: {:?}", name);
+            kodama.delete_project(&name).expect("delete project");
+        }
     }
 }
 
-fn service(kodama: Kodama, subcommand: ServiceSubCommand) {
+fn service(mut kodama: Kodama, subcommand: ServiceSubCommand) {
     match subcommand {
         ServiceSubCommand::Create {
             project,
@@ -145,21 +853,83 @@ fn service(kodama: Kodama, subcommand: ServiceSubCommand) {
             let services = kodama.service_list(&project).expect("service list");
             println!();
             println!("services:");
-            println!("{: >10} {: <80}", "[id]", "[name]");
+            println!("{: >10} {: <40} {: <80}", "[id]", "[name]", "[description]");
             for service in &services {
-                println!("{: >10} {: <80}", service.id, service.name);
+                println!(
+                    "{: >10} {: <40} {: <80}",
+                    service.id, service.name, service.description
+                );
             }
         }
+        ServiceSubCommand::Rename {
+            project,
+            name,
+            new_name,
+        } => {
+            tracing::debug!("renaming service: {:?} to {:?}", name, new_name);
+            kodama
+                .rename_service(&project, &name, &new_name)
+                .expect("rename service");
+        }
+        ServiceSubCommand::Describe {
+            project,
+            name,
+            description,
+        } => {
+            kodama
+                .set_service_description(&project, &name, &description)
+                .expect("describe service");
+        }
+        ServiceSubCommand::Delete { project, name } => {
+            tracing::debug!("deleting service: {:?}", name);
+            kodama
+                .delete_service(&project, &name)
+                .expect("delete service");
+        }
     }
 }
 
-fn matric(_kodama: Kodama, subcommand: MetricSubCommand) {
+fn matric(mut kodama: Kodama, subcommand: MetricSubCommand) {
     match subcommand {
+        MetricSubCommand::List { project, service } => {
+            let metrics = kodama.metric_list(&project, &service).expect("metric list");
+
+            println!();
+            println!("{: >10} {: <80}", "[id]", "[name]");
+            for metric in &metrics {
+                println!("{: >10} {: <80}", metric.id, metric.name);
+            }
+        }
+        MetricSubCommand::Data {
+            project,
+            service,
+            metric,
+            labels,
+        } => {
+            let values = kodama
+                .metric_values(&project, &service, &metric, &Labels::from_iter(labels))
+                .expect("metric values");
+
+            println!();
+            println!(
+                "{: <32} {: >20} {: <40}",
+                "[timestamp]", "[value]", "[labels]"
+            );
+            for value in &values {
+                println!(
+                    "{: <32} {: >20} {: <40}",
+                    timestamp_to_human(&value.timestamp),
+                    value.value,
+                    labels_to_human(&value.labels)
+                );
+            }
+        }
         MetricSubCommand::Push {
             project,
             service,
             metric,
             value,
+            labels,
         } => {
             tracing::debug!("pushing metric: {:?}", metric);
 
@@ -169,7 +939,7 @@ fn matric(_kodama: Kodama, subcommand: MetricSubCommand) {
                 SocketAddr::from(([127, 0, 0, 1], 49001)),
             );
 
-            instance.metric(&metric, value);
+            instance.metric_with_labels(&metric, value, labels);
         }
     }
 }
@@ -189,37 +959,318 @@ fn record(mut kodama: Kodama, subcommand: RecordSubCommand) {
             project,
             service,
             record,
+            since,
+            until,
+            percentiles,
+            labels,
+            group_by_label,
         } => {
             let mut queries = kodama
-                .record_entries(&project, &service, &record)
+                .record_entries(
+                    &project,
+                    &service,
+                    &record,
+                    since.as_ref(),
+                    until.as_ref(),
+                    &percentiles,
+                    &Labels::from_iter(labels),
+                    group_by_label.as_deref(),
+                )
                 .expect("record entries");
 
+            let percentile_headers = if percentiles.is_empty() {
+                format!("{: >10} {: >10}", "[p50]", "[p95]")
+            } else {
+                percentiles
+                    .iter()
+                    .map(|percentile| format!("{: >10}", format!("[p{}]", percentile)))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+
             println!();
             println!(
-                "{: >10} {: >10} {: >10} {: >10} {: >10} {: <80}",
-                "[total]", "[avg]", "[p50]", "[p95]", "[count]", "[query]"
+                "{: >10} {: >10} {} {: >10} {: >10} {: <80}",
+                "[total]", "[avg]", percentile_headers, "[count]", "[errors]", "[query]"
             );
-            queries.sort_by(|a, b| b.p95.cmp(&a.p95));
+            if percentiles.is_empty() {
+                queries.sort_by_key(|trace| std::cmp::Reverse(trace.p95));
+            } else {
+                queries.sort_by_key(|trace| {
+                    std::cmp::Reverse(trace.percentiles.iter().map(|p| p.value).max())
+                });
+            }
             for trace in &queries {
                 let query = if trace.group_by.len() > 80 - 3 {
                     format!("{}...", &trace.group_by[..(80 - 3)])
                 } else {
                     trace.group_by.clone()
                 };
+                let percentile_values = if percentiles.is_empty() {
+                    format!(
+                        "{: >10} {: >10}",
+                        us_to_human(trace.p50),
+                        us_to_human(trace.p95)
+                    )
+                } else {
+                    trace
+                        .percentiles
+                        .iter()
+                        .map(|percentile| format!("{: >10}", us_to_human(percentile.value)))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                };
                 println!(
-                    "{: >10} {: >10} {: >10} {: >10} {: >10} {: <80}",
+                    "{: >10} {: >10} {} {: >10} {: >10} {: <80}",
                     us_to_human(trace.execution_time),
                     us_to_human(trace.avg),
-                    us_to_human(trace.p50),
-                    us_to_human(trace.p95),
+                    percentile_values,
                     trace.count,
+                    error_rate_to_human(trace.errors, trace.count),
                     query
                 );
             }
         }
+        RecordSubCommand::Series {
+            project,
+            service,
+            record,
+            bucket,
+            group_by,
+            since,
+            until,
+            labels,
+        } => {
+            let entries = kodama
+                .record_series(
+                    &project,
+                    &service,
+                    &record,
+                    bucket,
+                    group_by.as_deref(),
+                    since.as_ref(),
+                    until.as_ref(),
+                    &Labels::from_iter(labels),
+                )
+                .expect("record series");
+
+            println!();
+            println!(
+                "{: <32} {: >10} {: >10} {: >10} {: >10} {: >10} {: >10}",
+                "[time]", "[count]", "[avg]", "[p50]", "[p95]", "[p99]", "[errors]"
+            );
+            for entry in &entries {
+                println!(
+                    "{: <32} {: >10} {: >10} {: >10} {: >10} {: >10} {: >10}",
+                    timestamp_to_human(&entry.timestamp),
+                    entry.count,
+                    us_to_human(entry.avg),
+                    us_to_human(entry.p50),
+                    us_to_human(entry.p95),
+                    us_to_human(entry.p99),
+                    error_rate_to_human(entry.errors, entry.count),
+                );
+            }
+        }
+        RecordSubCommand::Anomalies {
+            project,
+            service,
+            record,
+            window,
+            baseline,
+            windows,
+            sensitivity,
+            until,
+        } => {
+            let anomalies = kodama
+                .record_anomalies(
+                    &project,
+                    &service,
+                    &record,
+                    until.as_ref(),
+                    window,
+                    baseline,
+                    windows.unwrap_or_else(|| baseline.default_windows()),
+                    sensitivity,
+                )
+                .expect("record anomalies");
+
+            println!();
+            println!(
+                "{: <40} {: <10} {: >10} {: >10} {: >10} {: >10} {: >8}",
+                "[group by]", "[signal]", "[count]", "[value]", "[mean]", "[stddev]", "[score]"
+            );
+            for anomaly in &anomalies {
+                let value = |value: f64| match anomaly.signal {
+                    anomaly::Signal::P95 => us_to_human(value.round() as u64),
+                    anomaly::Signal::ErrorRate => format!("{:.2}%", value),
+                };
+                println!(
+                    "{: <40} {: <10} {: >10} {: >10} {: >10} {: >10} {: >8.1}",
+                    anomaly.group_by,
+                    anomaly.signal,
+                    anomaly.count,
+                    value(anomaly.value),
+                    value(anomaly.mean),
+                    value(anomaly.stddev),
+                    anomaly.score
+                );
+            }
+        }
+        RecordSubCommand::Delete {
+            project,
+            service,
+            record,
+            group_by: Some(group_by),
+        } => {
+            tracing::debug!("deleting group by {:?} of record {:?}", group_by, record);
+            let deleted = kodama
+                .delete_group(&project, &service, &record, &group_by)
+                .expect("delete group");
+            println!("deleted {} records", deleted);
+        }
+        RecordSubCommand::Delete {
+            project,
+            service,
+            record,
+            group_by: None,
+        } => {
+            tracing::debug!("deleting record: {:?}", record);
+            kodama
+                .delete_record(&project, &service, &record)
+                .expect("delete record");
+        }
+        RecordSubCommand::Truncate {
+            project,
+            service,
+            record,
+            before,
+        } => {
+            tracing::debug!("truncating record: {:?}", record);
+            let deleted = kodama
+                .truncate_record(&project, &service, &record, before.as_ref())
+                .expect("truncate record");
+            println!("deleted {} records", deleted);
+        }
+    }
+}
+
+/// Parse a duration such as `90s`, `15m`, `1h30m` or `7d` into microseconds.
+fn parse_duration(value: &str) -> Result<u64, String> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let amount = number
+            .parse::<u64>()
+            .map_err(|_| format!("invalid duration: {}", value))?;
+        number.clear();
+
+        let unit = match c {
+            's' => 1000 * 1000,
+            'm' => 1000 * 1000 * 60,
+            'h' => 1000 * 1000 * 60 * 60,
+            'd' => 1000 * 1000 * 60 * 60 * 24,
+            'w' => 1000 * 1000 * 60 * 60 * 24 * 7,
+            _ => return Err(format!("invalid duration unit '{}': {}", c, value)),
+        };
+        total = amount
+            .checked_mul(unit)
+            .and_then(|amount| total.checked_add(amount))
+            .ok_or_else(|| format!("duration too large: {}", value))?;
+    }
+
+    if !number.is_empty() || total == 0 {
+        return Err(format!("invalid duration: {}", value));
+    }
+
+    Ok(total)
+}
+
+fn parse_keep(value: &str) -> Result<Keep, String> {
+    match value {
+        "none" => Ok(Keep(None)),
+        value => parse_duration(value).map(|keep| Keep(Some(keep))),
     }
 }
 
+/// Parse a `key=value` label.
+fn parse_label(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid label, expected key=value: {}", value)),
+    }
+}
+
+/// Parse either a duration relative to now (e.g. `1h`) or an RFC3339 time.
+fn parse_time(value: &str) -> Result<Timestamp, String> {
+    if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(value) {
+        let microseconds = u64::try_from(datetime.timestamp_micros())
+            .map_err(|_| format!("time before unix epoch: {}", value))?;
+        return Ok(Timestamp { microseconds });
+    }
+
+    let duration = parse_duration(value)?;
+    let now = Timestamp::now().ok_or("unable to read current time")?;
+    Ok(Timestamp {
+        microseconds: now.microseconds.saturating_sub(duration),
+    })
+}
+
+fn timestamp_to_human(timestamp: &Timestamp) -> String {
+    match chrono::NaiveDateTime::from_timestamp_micros(timestamp.microseconds as i64) {
+        Some(datetime) => datetime
+            .and_utc()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        None => format!("{}us", timestamp.microseconds),
+    }
+}
+
+fn labels_to_human(labels: &Labels) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn error_rate_to_human(errors: i64, count: i64) -> String {
+    if errors == 0 || count == 0 {
+        "0".to_string()
+    } else {
+        format!("{} ({:.1}%)", errors, errors as f64 * 100.0 / count as f64)
+    }
+}
+
+/// Format microseconds the way `parse_duration` reads them, e.g. `1d12h`.
+fn duration_to_human(us: u64) -> String {
+    const UNITS: [(char, u64); 5] = [
+        ('w', 1000 * 1000 * 60 * 60 * 24 * 7),
+        ('d', 1000 * 1000 * 60 * 60 * 24),
+        ('h', 1000 * 1000 * 60 * 60),
+        ('m', 1000 * 1000 * 60),
+        ('s', 1000 * 1000),
+    ];
+
+    let mut output = String::new();
+    let mut rest = us;
+    for (unit, size) in UNITS {
+        if rest >= size {
+            output.push_str(&format!("{}{}", rest / size, unit));
+            rest %= size;
+        }
+    }
+
+    if output.is_empty() || rest > 0 {
+        output.push_str(&format!("{}us", rest));
+    }
+    output
+}
+
 fn us_to_human(us: u64) -> String {
     if us < 1000 {
         format!("{}us", us)
@@ -235,3 +1286,61 @@ fn us_to_human(us: u64) -> String {
         format!("{:.2}d", us as f64 / 1000.0 / 1000.0 / 60.0 / 60.0 / 24.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("90s"), Ok(90 * 1000 * 1000));
+        assert_eq!(parse_duration("1h30m"), Ok(90 * 60 * 1000 * 1000));
+        assert_eq!(parse_duration("7d"), Ok(7 * 24 * 60 * 60 * 1000 * 1000));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("15").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("99999999999999d").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+
+        assert_eq!(duration_to_human(parse_duration("30d").unwrap()), "4w2d");
+        assert_eq!(duration_to_human(parse_duration("1h30m").unwrap()), "1h30m");
+    }
+
+    #[test]
+    fn time() {
+        let time = parse_time("2023-12-24T18:00:00Z").unwrap();
+        assert_eq!(time.microseconds, 1703440800 * 1000 * 1000);
+
+        let time = parse_time("1h").unwrap();
+        let now = Timestamp::now().unwrap();
+        assert!(now.microseconds - time.microseconds >= 60 * 60 * 1000 * 1000);
+    }
+
+    #[test]
+    fn label() {
+        assert_eq!(
+            parse_label("host=web-1"),
+            Ok(("host".to_string(), "web-1".to_string()))
+        );
+        assert_eq!(
+            parse_label("query=a=b"),
+            Ok(("query".to_string(), "a=b".to_string()))
+        );
+        assert!(parse_label("host").is_err());
+        assert!(parse_label("=web-1").is_err());
+    }
+
+    #[test]
+    fn event_count_args() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["kodama", "event", "count", "shop", "api", "signup"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        assert!(parse(&["--bucket", "1d"]).is_ok());
+        assert!(parse(&["--by", "plan"]).is_ok());
+        assert!(parse(&["--bucket", "1d", "--by", "plan"]).is_err());
+    }
+}
@@ -0,0 +1,604 @@
+use crate::{
+    is_constraint_violation, is_valid_name, record::percentile_rank, ApiError, Error, Kodama,
+    Result, Service,
+};
+use kodama_api::Timestamp;
+
+/// Number of history entries returned without a limit.
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertTarget {
+    Record,
+    Metric,
+}
+
+text_enum!(AlertTarget, "alert target", {
+    Record => "record",
+    Metric => "metric",
+});
+
+/// How the samples within the window of a rule are reduced to a single
+/// value. Record aggregations are over execution times in microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    P50,
+    P90,
+    P95,
+    P99,
+    /// Number of records with an error
+    Errors,
+    /// Percentage of records with an error
+    ErrorRate,
+    /// Latest metric value
+    Last,
+}
+
+text_enum!(Aggregation, "aggregation", {
+    Count => "count",
+    Sum => "sum",
+    Avg => "avg",
+    Min => "min",
+    Max => "max",
+    P50 => "p50",
+    P90 => "p90",
+    P95 => "p95",
+    P99 => "p99",
+    Errors => "errors",
+    ErrorRate => "error_rate",
+    Last => "last",
+});
+
+impl Aggregation {
+    pub fn supports(&self, target: AlertTarget) -> bool {
+        match self {
+            Self::Errors | Self::ErrorRate => target == AlertTarget::Record,
+            Self::Last => target == AlertTarget::Metric,
+            _ => true,
+        }
+    }
+
+    /// Aggregate `values` sorted in ascending order. `None` if there is no
+    /// value to compare, i.e. the window is empty.
+    fn apply(&self, values: &[f64], errors: usize, last: Option<f64>) -> Option<f64> {
+        let count = values.len();
+        let percentile = |p: f64| values.get(percentile_rank(count, p)).copied();
+        match self {
+            Self::Count => Some(count as f64),
+            Self::Errors => Some(errors as f64),
+            _ if count == 0 => None,
+            Self::Sum => Some(values.iter().sum()),
+            Self::Avg => Some(values.iter().sum::<f64>() / count as f64),
+            Self::Min => values.first().copied(),
+            Self::Max => values.last().copied(),
+            Self::P50 => percentile(50.0),
+            Self::P90 => percentile(90.0),
+            Self::P95 => percentile(95.0),
+            Self::P99 => percentile(99.0),
+            Self::ErrorRate => Some(errors as f64 * 100.0 / count as f64),
+            Self::Last => last,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+text_enum!(Comparison, "comparison", {
+    Gt => "gt",
+    Ge => "ge",
+    Lt => "lt",
+    Le => "le",
+});
+
+impl Comparison {
+    pub fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Self::Gt => value > threshold,
+            Self::Ge => value >= threshold,
+            Self::Lt => value < threshold,
+            Self::Le => value <= threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    Ok,
+    Firing,
+}
+
+text_enum!(AlertState, "alert state", {
+    Ok => "ok",
+    Firing => "firing",
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertChange {
+    Firing,
+    Resolved,
+}
+
+text_enum!(AlertChange, "alert change", {
+    Firing => "firing",
+    Resolved => "resolved",
+});
+
+/// Fire while `aggregation` of the target over the last `window_us`
+/// compares to `threshold`, e.g. a record's p99 `gt` 100000 over 5 minutes.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CreateRequest {
+    pub project_name: String,
+    pub service_name: String,
+    pub alert_name: String,
+    pub target: AlertTarget,
+    /// Record or metric name
+    pub target_name: String,
+    pub aggregation: Aggregation,
+    /// Evaluation window in microseconds
+    pub window_us: u64,
+    pub comparison: Comparison,
+    pub threshold: f64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CreateResponse {
+    pub alert_id: i64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListRequest {
+    #[serde(default)]
+    pub project_name: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListResponse {
+    pub alerts: Vec<AlertRule>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AlertRule {
+    pub id: i64,
+    pub project_name: String,
+    pub service_name: String,
+    pub alert_name: String,
+    pub target: AlertTarget,
+    pub target_name: String,
+    pub aggregation: Aggregation,
+    pub window_us: u64,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    pub state: AlertState,
+    /// Value at the last evaluation, `None` if the window was empty
+    pub value: Option<f64>,
+    pub evaluated_at: Option<Timestamp>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryRequest {
+    pub project_name: String,
+    #[serde(default)]
+    pub service_name: Option<String>,
+    #[serde(default)]
+    pub alert_name: Option<String>,
+    /// Maximum number of entries, newest first
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryResponse {
+    pub entries: Vec<HistoryEntry>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+    pub project_name: String,
+    pub service_name: String,
+    pub alert_name: String,
+    pub timestamp: Timestamp,
+    pub change: AlertChange,
+    /// Value that caused the change
+    pub value: f64,
+}
+
+/// A rule that started firing or resolved during an evaluation.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AlertTransition {
+    /// The rule after the evaluation
+    pub alert: AlertRule,
+    pub change: AlertChange,
+    pub value: f64,
+    pub timestamp: Timestamp,
+}
+
+impl Service {
+    /// Execution times in ascending order and the number of errors of the
+    /// records within `[from, to)`.
+    fn record_window(
+        &self,
+        record_id: i64,
+        from: &Timestamp,
+        to: &Timestamp,
+    ) -> Result<(Vec<f64>, usize)> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT execution_time_us, error FROM record_{}
+            WHERE timestamp >= ?1 AND timestamp < ?2
+            ORDER BY execution_time_us ASC",
+            record_id
+        ))?;
+        let mut rows = stmt.query(rusqlite::params![from, to])?;
+
+        let mut values = Vec::new();
+        let mut errors = 0;
+        while let Some(row) = rows.next()? {
+            values.push(row.get::<_, i64>(0)? as f64);
+            if row.get::<_, i64>(1)? > 0 {
+                errors += 1;
+            }
+        }
+        Ok((values, errors))
+    }
+
+    /// Metric values within `[from, to)` in ascending order, and the latest
+    /// of them.
+    fn metric_window(
+        &self,
+        metric_id: i64,
+        from: &Timestamp,
+        to: &Timestamp,
+    ) -> Result<(Vec<f64>, Option<f64>)> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT value FROM metric_{}
+            WHERE timestamp >= ?1 AND timestamp < ?2
+            ORDER BY timestamp ASC",
+            metric_id
+        ))?;
+        let mut values = stmt
+            .query_map(rusqlite::params![from, to], |row| row.get::<_, f64>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let last = values.last().copied();
+        values.sort_by(f64::total_cmp);
+        Ok((values, last))
+    }
+}
+
+impl Kodama {
+    pub fn create_alert(&self, request: &CreateRequest) -> Result<i64> {
+        let invalid = |reason: String| Err(ApiError::InvalidAlertRule(reason).into());
+        if !is_valid_name(&request.alert_name) {
+            return invalid(format!("invalid name: {:?}", request.alert_name));
+        }
+        if request.window_us == 0 {
+            return invalid("window must be positive".to_string());
+        }
+        if !request.threshold.is_finite() {
+            return invalid("threshold must be a finite number".to_string());
+        }
+        if !request.aggregation.supports(request.target) {
+            return invalid(format!(
+                "{} is not supported for a {}",
+                request.aggregation, request.target
+            ));
+        }
+
+        let service_id = self.get_service_id(&request.project_name, &request.service_name)?;
+        let mut stmt = self.db.prepare(
+            "INSERT INTO alert_rules
+            (service_id, alert_name, target, target_name, aggregation, window_us, comparison, threshold)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        let alert_id = stmt
+            .insert(rusqlite::params![
+                service_id,
+                request.alert_name,
+                request.target,
+                request.target_name,
+                request.aggregation,
+                request.window_us,
+                request.comparison,
+                request.threshold
+            ])
+            .map_err(|err| match is_constraint_violation(&err) {
+                true => ApiError::AlertAlreadyExists(request.alert_name.clone()).into(),
+                false => Error::from(err),
+            })?;
+        Ok(alert_id)
+    }
+
+    pub fn alert_list(&self, project_name: Option<&str>) -> Result<Vec<AlertRule>> {
+        let mut stmt = self.db.prepare(
+            "SELECT a.alert_id, p.project_name, s.service_name, a.alert_name, a.target,
+            a.target_name, a.aggregation, a.window_us, a.comparison, a.threshold, a.state,
+            a.value, a.evaluated_at
+            FROM alert_rules AS a
+            JOIN services AS s ON a.service_id = s.service_id
+            JOIN projects AS p ON s.project_id = p.project_id
+            WHERE ?1 IS NULL OR p.project_name = ?1
+            ORDER BY p.project_name, s.service_name, a.alert_name",
+        )?;
+        let alerts = stmt
+            .query_map(rusqlite::params![project_name], |row| {
+                Ok(AlertRule {
+                    id: row.get(0)?,
+                    project_name: row.get(1)?,
+                    service_name: row.get(2)?,
+                    alert_name: row.get(3)?,
+                    target: row.get(4)?,
+                    target_name: row.get(5)?,
+                    aggregation: row.get(6)?,
+                    window_us: row.get(7)?,
+                    comparison: row.get(8)?,
+                    threshold: row.get(9)?,
+                    state: row.get(10)?,
+                    value: row.get(11)?,
+                    evaluated_at: row.get(12)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(alerts)
+    }
+
+    /// State changes of the alerts of a project, newest first.
+    pub fn alert_history(
+        &self,
+        project_name: &str,
+        service_name: Option<&str>,
+        alert_name: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        let project_id = self.get_project_id(project_name)?;
+        let service_id = match service_name {
+            Some(service_name) => Some(self.get_service_id(project_name, service_name)?),
+            None => None,
+        };
+        if let Some(alert_name) = alert_name {
+            let exists: bool = self.db.query_row(
+                "SELECT EXISTS(SELECT 1 FROM alert_rules AS a
+                JOIN services AS s ON a.service_id = s.service_id
+                WHERE s.project_id = ?1 AND (?2 IS NULL OR s.service_id = ?2) AND a.alert_name = ?3)",
+                rusqlite::params![project_id, service_id, alert_name],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                return Err(ApiError::AlertNotFound(alert_name.to_string()).into());
+            }
+        }
+
+        let mut stmt = self.db.prepare(
+            "SELECT p.project_name, s.service_name, a.alert_name, h.timestamp, h.change, h.value
+            FROM alert_history AS h
+            JOIN alert_rules AS a ON h.alert_id = a.alert_id
+            JOIN services AS s ON a.service_id = s.service_id
+            JOIN projects AS p ON s.project_id = p.project_id
+            WHERE p.project_id = ?1 AND (?2 IS NULL OR s.service_id = ?2)
+            AND (?3 IS NULL OR a.alert_name = ?3)
+            ORDER BY h.timestamp DESC, h.history_id DESC
+            LIMIT ?4",
+        )?;
+        let entries = stmt
+            .query_map(
+                rusqlite::params![project_id, service_id, alert_name, limit],
+                |row| {
+                    Ok(HistoryEntry {
+                        project_name: row.get(0)?,
+                        service_name: row.get(1)?,
+                        alert_name: row.get(2)?,
+                        timestamp: row.get(3)?,
+                        change: row.get(4)?,
+                        value: row.get(5)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Evaluate every alert rule over the window ending at `now`, recording
+    /// and returning the rules that started firing or resolved. A rule
+    /// without samples in its window keeps its state, and a rule that fails
+    /// to evaluate or store is logged and skipped.
+    pub fn evaluate_alerts(&mut self, now: &Timestamp) -> Result<Vec<AlertTransition>> {
+        let mut transitions = Vec::new();
+        for mut alert in self.alert_list(None)? {
+            let value = match self.alert_value(&alert, now) {
+                Ok(value) => value,
+                Err(err) => {
+                    tracing::error!("unable to evaluate alert {}: {:?}", alert.alert_name, err);
+                    continue;
+                }
+            };
+
+            let previous = alert.state;
+            if let Some(value) = value {
+                alert.state = match alert.comparison.holds(value, alert.threshold) {
+                    true => AlertState::Firing,
+                    false => AlertState::Ok,
+                };
+            }
+            alert.value = value;
+            alert.evaluated_at = Some(now.clone());
+
+            let change = match (previous, alert.state) {
+                (AlertState::Ok, AlertState::Firing) => Some(AlertChange::Firing),
+                (AlertState::Firing, AlertState::Ok) => Some(AlertChange::Resolved),
+                _ => None,
+            };
+            let value = value.unwrap_or_default();
+            if let Err(err) = self.store_evaluation(&alert, change, value, now) {
+                tracing::error!("unable to store alert {}: {:?}", alert.alert_name, err);
+                continue;
+            }
+
+            if let Some(change) = change {
+                transitions.push(AlertTransition {
+                    alert,
+                    change,
+                    value,
+                    timestamp: now.clone(),
+                });
+            }
+        }
+        Ok(transitions)
+    }
+
+    /// Store the state of an evaluated alert, together with its change if
+    /// it started firing or resolved.
+    fn store_evaluation(
+        &self,
+        alert: &AlertRule,
+        change: Option<AlertChange>,
+        value: f64,
+        now: &Timestamp,
+    ) -> Result<()> {
+        let transaction = self.db.unchecked_transaction()?;
+        transaction.execute(
+            "UPDATE alert_rules SET state = ?2, value = ?3, evaluated_at = ?4 WHERE alert_id = ?1",
+            rusqlite::params![alert.id, alert.state, alert.value, now],
+        )?;
+        if let Some(change) = change {
+            transaction.execute(
+                "INSERT INTO alert_history (alert_id, timestamp, change, value) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![alert.id, now, change, value],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn alert_value(&mut self, alert: &AlertRule, now: &Timestamp) -> Result<Option<f64>> {
+        let service = self.get_service(&alert.project_name, &alert.service_name)?;
+        let service_id = service.borrow().id;
+        let from = Timestamp {
+            microseconds: now.microseconds.saturating_sub(alert.window_us),
+        };
+
+        // a target without samples so far is aggregated as an empty window
+        let value = match alert.target {
+            AlertTarget::Record => match self.get_record_id(service_id, &alert.target_name) {
+                Ok(record_id) => {
+                    let (values, errors) = service.borrow().record_window(record_id, &from, now)?;
+                    alert.aggregation.apply(&values, errors, None)
+                }
+                Err(Error::ApiError(ApiError::RecordNotFound(_))) => {
+                    alert.aggregation.apply(&[], 0, None)
+                }
+                Err(err) => return Err(err),
+            },
+            AlertTarget::Metric => match self.get_metric_id(service_id, &alert.target_name) {
+                Ok(metric_id) => {
+                    let (values, last) = service.borrow().metric_window(metric_id, &from, now)?;
+                    alert.aggregation.apply(&values, 0, last)
+                }
+                Err(Error::ApiError(ApiError::MetricNotFound(_))) => {
+                    alert.aggregation.apply(&[], 0, None)
+                }
+                Err(err) => return Err(err),
+            },
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{add_record_at, at, TestDatabase};
+
+    #[test]
+    fn evaluate() {
+        let database = TestDatabase::new("alerts");
+        let mut kodama = database.with_alert("slow checkout");
+        let changes = |transitions: Vec<AlertTransition>| {
+            transitions
+                .into_iter()
+                .map(|transition| (transition.change, transition.value))
+                .collect::<Vec<_>>()
+        };
+
+        add_record_at(&mut kodama, 10, 50);
+        assert!(kodama.evaluate_alerts(&at(30)).unwrap().is_empty());
+        add_record_at(&mut kodama, 40, 500);
+        assert_eq!(
+            changes(kodama.evaluate_alerts(&at(60)).unwrap()),
+            [(AlertChange::Firing, 500.0)]
+        );
+
+        // an empty window keeps the alert firing
+        assert!(kodama.evaluate_alerts(&at(200)).unwrap().is_empty());
+        let alert = kodama.alert_list(None).unwrap().remove(0);
+        assert_eq!(alert.state, AlertState::Firing);
+        assert_eq!(alert.value, None);
+        assert_eq!(
+            alert.evaluated_at.map(|timestamp| timestamp.microseconds),
+            Some(at(200).microseconds)
+        );
+
+        add_record_at(&mut kodama, 210, 20);
+        assert_eq!(
+            changes(kodama.evaluate_alerts(&at(240)).unwrap()),
+            [(AlertChange::Resolved, 20.0)]
+        );
+        let history = kodama
+            .alert_history("shop", None, None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.timestamp.microseconds, entry.change, entry.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            history,
+            [
+                (at(240).microseconds, AlertChange::Resolved, 20.0),
+                (at(60).microseconds, AlertChange::Firing, 500.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregations() {
+        let values = (1..=100).map(|value| value as f64).collect::<Vec<_>>();
+        assert_eq!(Aggregation::Count.apply(&values, 5, None), Some(100.0));
+        assert_eq!(Aggregation::Sum.apply(&values, 5, None), Some(5050.0));
+        assert_eq!(Aggregation::Avg.apply(&values, 5, None), Some(50.5));
+        assert_eq!(Aggregation::Min.apply(&values, 5, None), Some(1.0));
+        assert_eq!(Aggregation::Max.apply(&values, 5, None), Some(100.0));
+        assert_eq!(Aggregation::P50.apply(&values, 5, None), Some(50.0));
+        assert_eq!(Aggregation::P99.apply(&values, 5, None), Some(99.0));
+        assert_eq!(Aggregation::Errors.apply(&values, 5, None), Some(5.0));
+        assert_eq!(Aggregation::ErrorRate.apply(&values, 5, None), Some(5.0));
+        assert_eq!(Aggregation::Last.apply(&values, 0, Some(7.0)), Some(7.0));
+
+        assert_eq!(Aggregation::Count.apply(&[], 0, None), Some(0.0));
+        assert_eq!(Aggregation::P99.apply(&[], 0, None), None);
+        assert_eq!(Aggregation::ErrorRate.apply(&[], 0, None), None);
+
+        assert!(Comparison::Gt.holds(2.0, 1.0));
+        assert!(!Comparison::Gt.holds(1.0, 1.0));
+        assert!(Comparison::Le.holds(1.0, 1.0));
+
+        assert!(!Aggregation::ErrorRate.supports(AlertTarget::Metric));
+        assert!(!Aggregation::Last.supports(AlertTarget::Record));
+        assert_eq!("error_rate".parse(), Ok(Aggregation::ErrorRate));
+        assert_eq!(
+            serde_json::to_string(&Aggregation::ErrorRate).unwrap(),
+            "\"error_rate\""
+        );
+    }
+}
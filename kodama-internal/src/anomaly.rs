@@ -0,0 +1,294 @@
+use crate::{record::DataEntry, ApiError, Kodama, Result};
+use kodama_api::{Labels, Timestamp};
+use std::collections::HashMap;
+
+/// Minimum number of records in a window for its group to be compared.
+pub const MIN_COUNT: i64 = 5;
+/// Minimum number of baseline windows a group needs samples in.
+pub const MIN_BASELINE_WINDOWS: usize = 3;
+/// Default z-score above which a window is flagged.
+pub const DEFAULT_SENSITIVITY: f64 = 3.0;
+
+const WEEK_US: u64 = 1000 * 1000 * 60 * 60 * 24 * 7;
+
+/// The trailing windows the current window is compared to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Baseline {
+    /// The windows directly preceding the current one
+    Rolling,
+    /// The same window in previous weeks, e.g. the same hour last week
+    Weekly,
+}
+
+text_enum!(Baseline, "baseline", {
+    Rolling => "rolling",
+    Weekly => "weekly",
+});
+
+impl Baseline {
+    /// Number of baseline windows used without an explicit count.
+    pub fn default_windows(&self) -> usize {
+        match self {
+            Self::Rolling => 24,
+            Self::Weekly => 4,
+        }
+    }
+
+    fn period_us(&self, window_us: u64) -> u64 {
+        match self {
+            Self::Rolling => window_us,
+            Self::Weekly => WEEK_US,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    /// Execution time 95th percentile in microseconds
+    P95,
+    /// Percentage of records with an error
+    ErrorRate,
+}
+
+text_enum!(Signal, "signal", {
+    P95 => "p95",
+    ErrorRate => "error_rate",
+});
+
+impl Signal {
+    fn value(&self, entry: &DataEntry) -> f64 {
+        match self {
+            Self::P95 => entry.p95 as f64,
+            Self::ErrorRate => entry.errors as f64 * 100.0 / entry.count.max(1) as f64,
+        }
+    }
+
+    /// Lower bound of the standard deviation, so that a perfectly stable
+    /// baseline does not flag negligible changes.
+    fn min_stddev(&self, mean: f64) -> f64 {
+        match self {
+            Self::P95 => (mean * 0.05).max(1.0),
+            Self::ErrorRate => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AnomalyRequest {
+    pub project_name: String,
+    pub service_name: String,
+    pub record_name: String,
+    /// Width of the compared windows in microseconds
+    pub window_us: u64,
+    pub baseline: Baseline,
+    /// Number of baseline windows, see `Baseline::default_windows`
+    #[serde(default)]
+    pub windows: Option<usize>,
+    /// Z-score above which a window is flagged
+    #[serde(default)]
+    pub sensitivity: Option<f64>,
+    /// End of the current window, now if not set
+    #[serde(default)]
+    pub to: Option<Timestamp>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AnomalyResponse {
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// A group whose current window regressed compared to its baseline.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Anomaly {
+    pub group_by: String,
+    pub signal: Signal,
+    /// Number of records in the current window
+    pub count: i64,
+    /// Value in the current window
+    pub value: f64,
+    /// Mean of the baseline windows
+    pub mean: f64,
+    /// Standard deviation of the baseline windows
+    pub stddev: f64,
+    /// Standard deviations the value is above the mean
+    pub score: f64,
+}
+
+/// Compare the groups of the `current` window to the `baseline` windows,
+/// returning the significant regressions, highest score first.
+fn detect(current: &[DataEntry], baseline: &[Vec<DataEntry>], sensitivity: f64) -> Vec<Anomaly> {
+    let mut history: HashMap<&str, Vec<&DataEntry>> = HashMap::new();
+    for entry in baseline.iter().flatten() {
+        if entry.count >= MIN_COUNT {
+            history.entry(&entry.group_by).or_default().push(entry);
+        }
+    }
+
+    let mut anomalies = Vec::new();
+    for entry in current.iter().filter(|entry| entry.count >= MIN_COUNT) {
+        let Some(history) = history
+            .get(entry.group_by.as_str())
+            .filter(|history| history.len() >= MIN_BASELINE_WINDOWS)
+        else {
+            continue;
+        };
+
+        for signal in [Signal::P95, Signal::ErrorRate] {
+            let values = history
+                .iter()
+                .map(|entry| signal.value(entry))
+                .collect::<Vec<_>>();
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let variance = values
+                .iter()
+                .map(|value| (value - mean).powi(2))
+                .sum::<f64>()
+                / (values.len() - 1) as f64;
+            let stddev = variance.sqrt();
+
+            let value = signal.value(entry);
+            let score = (value - mean) / stddev.max(signal.min_stddev(mean));
+            if score >= sensitivity {
+                anomalies.push(Anomaly {
+                    group_by: entry.group_by.clone(),
+                    signal,
+                    count: entry.count,
+                    value,
+                    mean,
+                    stddev,
+                    score,
+                });
+            }
+        }
+    }
+
+    anomalies.sort_by(|a, b| b.score.total_cmp(&a.score));
+    anomalies
+}
+
+impl Kodama {
+    /// Groups of a record whose p95 or error rate in the window ending at
+    /// `to`, or now, is significantly above the mean of the baseline windows.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_anomalies(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        record_name: &str,
+        to: Option<&Timestamp>,
+        window_us: u64,
+        baseline: Baseline,
+        windows: usize,
+        sensitivity: f64,
+    ) -> Result<Vec<Anomaly>> {
+        let invalid = |reason: &str| Err(ApiError::InvalidAnomalyQuery(reason.to_string()).into());
+        if window_us == 0 {
+            return invalid("window must be positive");
+        }
+        if windows < MIN_BASELINE_WINDOWS {
+            return invalid(&format!(
+                "at least {} baseline windows are required",
+                MIN_BASELINE_WINDOWS
+            ));
+        }
+        if !(sensitivity.is_finite() && sensitivity > 0.0) {
+            return invalid("sensitivity must be positive");
+        }
+
+        let to = match to {
+            Some(to) => to.clone(),
+            None => Timestamp::now().ok_or(ApiError::InvalidTimestamp)?,
+        };
+        let period_us = baseline.period_us(window_us);
+        let mut entries = |end: u64| {
+            let from = Timestamp {
+                microseconds: end.saturating_sub(window_us),
+            };
+            let to = Timestamp { microseconds: end };
+            self.record_entries(
+                project_name,
+                service_name,
+                record_name,
+                Some(&from),
+                Some(&to),
+                &[],
+                &Labels::new(),
+                None,
+            )
+        };
+
+        let current = entries(to.microseconds)?;
+        let mut history = Vec::with_capacity(windows);
+        for window in 1..=windows as u64 {
+            match to.microseconds.checked_sub(window * period_us) {
+                Some(end) if end > 0 => history.push(entries(end)?),
+                _ => break,
+            }
+        }
+
+        Ok(detect(&current, &history, sensitivity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(group_by: &str, count: i64, errors: i64, p95: u64) -> DataEntry {
+        DataEntry {
+            group_by: group_by.to_string(),
+            count,
+            errors,
+            execution_time: 0,
+            min: 0,
+            max: 0,
+            avg: 0,
+            p50: 0,
+            p95,
+            percentiles: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn detect_regressions() {
+        let baseline = [1000, 1100, 900, 1000]
+            .into_iter()
+            .map(|p95| {
+                vec![
+                    entry("/index", 100, 1, p95),
+                    entry("/login", 100, 0, 500),
+                    entry("/rare", 2, 0, 100),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        let anomalies = detect(
+            &[
+                entry("/index", 100, 1, 2000),
+                entry("/login", 100, 20, 510),
+                entry("/rare", 100, 50, 9000),
+                entry("/new", 100, 50, 9000),
+            ],
+            &baseline,
+            DEFAULT_SENSITIVITY,
+        );
+        let flagged = anomalies
+            .iter()
+            .map(|anomaly| (anomaly.group_by.as_str(), anomaly.signal))
+            .collect::<Vec<_>>();
+        // an error rate of 20% over a stable 0% scores 20, the p95 about 12
+        assert_eq!(
+            flagged,
+            [("/login", Signal::ErrorRate), ("/index", Signal::P95)]
+        );
+        assert_eq!(anomalies[1].mean, 1000.0);
+
+        // improvements and small changes are not flagged
+        assert!(detect(&[entry("/index", 100, 0, 1150)], &baseline, 3.0).is_empty());
+        assert!(detect(&[entry("/index", 100, 0, 100)], &baseline, 3.0).is_empty());
+        // too few baseline windows
+        assert!(detect(&[entry("/index", 100, 1, 2000)], &baseline[..2], 3.0).is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::Result;
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+/// Group inserts into one transaction per service database, committed once
+/// `max_rows` rows have been written or `flush_interval` has passed since
+/// the first of them, instead of committing (and syncing) every row.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteBatching {
+    pub max_rows: usize,
+    pub flush_interval: Duration,
+}
+
+impl Default for WriteBatching {
+    fn default() -> Self {
+        Self {
+            max_rows: 1000,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The open batch transaction of a single connection.
+#[derive(Debug)]
+pub(crate) struct WriteBatch {
+    batching: WriteBatching,
+    started: Cell<Option<Instant>>,
+    rows: Cell<usize>,
+    /// Set while `Kodama::service_transaction` has a savepoint open, the
+    /// batch must not be committed underneath it.
+    held: Cell<bool>,
+}
+
+impl WriteBatch {
+    pub fn new(batching: WriteBatching) -> Self {
+        Self {
+            batching,
+            started: Cell::new(None),
+            rows: Cell::new(0),
+            held: Cell::new(false),
+        }
+    }
+
+    /// Open the batch transaction, if it isn't already.
+    pub fn begin(&self, db: &rusqlite::Connection) -> Result<()> {
+        if self.started.get().is_none() {
+            db.execute_batch("BEGIN IMMEDIATE;")?;
+            self.started.set(Some(Instant::now()));
+        }
+        Ok(())
+    }
+
+    /// Count a written row and commit if the batch is full.
+    pub fn written(&self, db: &rusqlite::Connection) -> Result<()> {
+        self.rows.set(self.rows.get() + 1);
+        self.commit_if_full(db)
+    }
+
+    pub fn commit_if_full(&self, db: &rusqlite::Connection) -> Result<()> {
+        if self.rows.get() >= self.batching.max_rows {
+            self.commit(db)?;
+        }
+        Ok(())
+    }
+
+    pub fn hold(&self, held: bool) {
+        self.held.set(held);
+    }
+
+    /// When the batch has to be committed, if one is open.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started
+            .get()
+            .map(|started| started + self.batching.flush_interval)
+    }
+
+    pub fn commit(&self, db: &rusqlite::Connection) -> Result<()> {
+        if self.started.get().is_none() || self.held.get() {
+            return Ok(());
+        }
+
+        tracing::debug!("commit write batch ({} rows)", self.rows.get());
+        // a failed commit leaves the transaction open and is retried later
+        db.execute_batch("COMMIT;")?;
+        self.started.set(None);
+        self.rows.set(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_when_full() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch("CREATE TABLE t (x INTEGER);").unwrap();
+
+        let batch = WriteBatch::new(WriteBatching {
+            max_rows: 2,
+            flush_interval: Duration::from_secs(60),
+        });
+        for x in 0..3 {
+            batch.begin(&db).unwrap();
+            db.execute("INSERT INTO t (x) VALUES (?1)", [x]).unwrap();
+            batch.written(&db).unwrap();
+        }
+        // the first two rows are committed, the third is still pending
+        assert!(!db.is_autocommit());
+        assert!(batch.deadline().is_some());
+
+        batch.hold(true);
+        batch.commit(&db).unwrap();
+        assert!(!db.is_autocommit());
+
+        batch.hold(false);
+        batch.commit(&db).unwrap();
+        assert!(db.is_autocommit());
+        assert!(batch.deadline().is_none());
+    }
+}
@@ -4,34 +4,153 @@ pub enum Error {
     Rusqlite(#[from] rusqlite::Error),
     #[error("api error: {0}")]
     ApiError(#[from] ApiError),
+    #[error("migration error: {0}")]
+    Migration(#[from] kodama_api::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
+/// Errors reported to API consumers. Every variant has a stable error code,
+/// grouped by the leading digit: 1xxxx invalid input, 2xxxx not found,
+/// 3xxxx conflict and 5xxxx server errors.
 #[derive(Debug, thiserror::Error)]
 pub enum ApiError {
-    #[error("invalid project name")]
-    InvalidProjectName,
-    #[error("invalid service name")]
-    InvalidServiceName,
-    #[error("project not found")]
-    ProjectNotFound,
-    #[error("service not found")]
-    ServiceNotFound(String),
+    #[error("invalid project name: {0:?}")]
+    InvalidProjectName(String),
+    #[error("invalid service name: {0:?}")]
+    InvalidServiceName(String),
     #[error("invalid timestamp")]
     InvalidTimestamp,
-    #[error("record not found")]
-    RecordNotFound,
+    #[error("invalid percentile: {0}")]
+    InvalidPercentile(f64),
+    #[error("invalid retention: {0}")]
+    InvalidRetention(String),
+    #[error("invalid label: {0:?}")]
+    InvalidLabel(String),
+    #[error("invalid event field: {0:?}")]
+    InvalidEventField(String),
+    #[error("invalid alert rule: {0}")]
+    InvalidAlertRule(String),
+    #[error("invalid notifier: {0}")]
+    InvalidNotifier(String),
+    #[error("invalid anomaly query: {0}")]
+    InvalidAnomalyQuery(String),
+    #[error("invalid label query: labels are only kept for records since {0}us")]
+    InvalidLabelRange(u64),
+    #[error("project not found: {0}")]
+    ProjectNotFound(String),
+    #[error("service not found: {0}")]
+    ServiceNotFound(String),
+    #[error("record not found: {0}")]
+    RecordNotFound(String),
+    #[error("metric not found: {0}")]
+    MetricNotFound(String),
+    #[error("alert not found: {0}")]
+    AlertNotFound(String),
+    #[error("notifier not found: {0}")]
+    NotifierNotFound(String),
+    #[error("project already exists: {0}")]
+    ProjectAlreadyExists(String),
+    #[error("service already exists: {0}")]
+    ServiceAlreadyExists(String),
+    #[error("alert already exists: {0}")]
+    AlertAlreadyExists(String),
+    #[error("notifier already exists: {0}")]
+    NotifierAlreadyExists(String),
+    #[error("database is in use by a running server")]
+    DatabaseInUse,
     #[error("unable to create database path")]
     UnableToCreateDatabasePath,
 }
 
 impl ApiError {
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::InvalidProjectName(_) => 10001,
+            Self::InvalidServiceName(_) => 10002,
+            Self::InvalidTimestamp => 10003,
+            Self::InvalidPercentile(_) => 10004,
+            Self::InvalidRetention(_) => 10005,
+            Self::InvalidLabel(_) => 10006,
+            Self::InvalidEventField(_) => 10007,
+            Self::InvalidAlertRule(_) => 10008,
+            Self::InvalidNotifier(_) => 10009,
+            Self::InvalidAnomalyQuery(_) => 10010,
+            Self::InvalidLabelRange(_) => 10011,
+            Self::ProjectNotFound(_) => 20001,
+            Self::ServiceNotFound(_) => 20002,
+            Self::RecordNotFound(_) => 20003,
+            Self::MetricNotFound(_) => 20004,
+            Self::AlertNotFound(_) => 20005,
+            Self::NotifierNotFound(_) => 20006,
+            Self::ProjectAlreadyExists(_) => 30001,
+            Self::ServiceAlreadyExists(_) => 30002,
+            Self::AlertAlreadyExists(_) => 30003,
+            Self::NotifierAlreadyExists(_) => 30004,
+            Self::DatabaseInUse => 30005,
+            Self::UnableToCreateDatabasePath => 50001,
+        }
+    }
+
     pub fn json(&self) -> kodama_api::ErrorResponse {
         tracing::error!("{:?}", self);
         kodama_api::ErrorResponse {
-            code: 10001,
-            message: "invalid project name".to_string(),
+            code: self.code(),
+            message: self.to_string(),
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_response_round_trip() {
+        let errors = [
+            ApiError::InvalidProjectName("a/b".to_string()),
+            ApiError::InvalidServiceName("".to_string()),
+            ApiError::InvalidTimestamp,
+            ApiError::InvalidPercentile(101.0),
+            ApiError::InvalidRetention("keep must be positive".to_string()),
+            ApiError::InvalidLabel("host name".to_string()),
+            ApiError::InvalidEventField("user..id".to_string()),
+            ApiError::InvalidAlertRule("window must be positive".to_string()),
+            ApiError::InvalidNotifier("webhook url must start with http://".to_string()),
+            ApiError::InvalidAnomalyQuery("window must be positive".to_string()),
+            ApiError::InvalidLabelRange(60_000_000),
+            ApiError::ProjectNotFound("project".to_string()),
+            ApiError::ServiceNotFound("service".to_string()),
+            ApiError::RecordNotFound("record".to_string()),
+            ApiError::MetricNotFound("metric".to_string()),
+            ApiError::AlertNotFound("alert".to_string()),
+            ApiError::NotifierNotFound("notifier".to_string()),
+            ApiError::ProjectAlreadyExists("project".to_string()),
+            ApiError::ServiceAlreadyExists("service".to_string()),
+            ApiError::AlertAlreadyExists("alert".to_string()),
+            ApiError::NotifierAlreadyExists("notifier".to_string()),
+            ApiError::DatabaseInUse,
+            ApiError::UnableToCreateDatabasePath,
+        ];
+
+        let mut codes = std::collections::HashSet::new();
+        for error in &errors {
+            let response = error.json();
+            assert!(
+                codes.insert(response.code),
+                "duplicate code {}",
+                response.code
+            );
+
+            let json = serde_json::to_string(&response).unwrap();
+            let decoded: kodama_api::ErrorResponse = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded, response);
+        }
+
+        let response = ApiError::ServiceNotFound("billing-api".to_string()).json();
+        assert_eq!(response.code, 20002);
+        assert_eq!(response.message, "service not found: billing-api");
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{ApiError, Kodama, Result, Service};
+use kodama_api::Timestamp;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListRequest {
+    pub project_name: String,
+    pub service_name: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListResponse {
+    pub events: Vec<ListEvent>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListEvent {
+    pub name: String,
+    /// Number of stored events
+    pub count: i64,
+    /// Time of the latest event
+    pub last: Timestamp,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CountRequest {
+    pub project_name: String,
+    pub service_name: String,
+    pub event_name: String,
+    /// Bucket width in microseconds
+    pub bucket: u64,
+    /// Only include events at or after this time
+    #[serde(default)]
+    pub from: Option<Timestamp>,
+    /// Only include events before this time
+    #[serde(default)]
+    pub to: Option<Timestamp>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CountResponse {
+    pub entries: Vec<CountEntry>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CountEntry {
+    /// Start of the bucket
+    pub timestamp: Timestamp,
+    pub count: i64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BreakdownRequest {
+    pub project_name: String,
+    pub service_name: String,
+    pub event_name: String,
+    /// Payload field to break down by, nested fields are separated by `.`
+    /// (e.g. `user.country`)
+    pub field: String,
+    /// Only include events at or after this time
+    #[serde(default)]
+    pub from: Option<Timestamp>,
+    /// Only include events before this time
+    #[serde(default)]
+    pub to: Option<Timestamp>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BreakdownResponse {
+    pub entries: Vec<BreakdownEntry>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BreakdownEntry {
+    /// Field value, empty for events without the field. Booleans are
+    /// `true` or `false`
+    pub value: String,
+    pub count: i64,
+}
+
+/// JSON path of a payload `field` such as `user.country`. Each part may only
+/// contain ASCII letters, digits, `_` and `-`.
+fn field_path(field: &str) -> Result<String> {
+    let mut path = "$".to_string();
+    for part in field.split('.') {
+        if part.is_empty()
+            || !part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+        {
+            return Err(ApiError::InvalidEventField(field.to_string()).into());
+        }
+        path.push_str(&format!(".\"{}\"", part));
+    }
+    Ok(path)
+}
+
+impl Service {
+    pub fn add_event(
+        &self,
+        timestamp: Option<Timestamp>,
+        event_name: &str,
+        payload: &serde_json::Value,
+    ) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(
+            "INSERT INTO events (timestamp, event_name, payload) VALUES (?1, ?2, ?3)",
+        )?;
+
+        let timestamp = if let Some(timestamp) = timestamp {
+            timestamp
+        } else if let Some(timestamp) = Timestamp::now() {
+            timestamp
+        } else {
+            return Err(ApiError::InvalidTimestamp.into());
+        };
+
+        self.begin_write()?;
+        stmt.execute(rusqlite::params![
+            timestamp,
+            event_name,
+            payload.to_string()
+        ])?;
+        self.end_write()
+    }
+
+    pub fn event_list(&self) -> Result<Vec<ListEvent>> {
+        let mut stmt = self.db.prepare(
+            "SELECT event_name, COUNT(*), MAX(timestamp) FROM events GROUP BY event_name ORDER BY event_name",
+        )?;
+        let events = stmt
+            .query_map(rusqlite::params![], |row| {
+                Ok(ListEvent {
+                    name: row.get(0)?,
+                    count: row.get(1)?,
+                    last: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(events)
+    }
+
+    /// Number of events per time bucket, aligned like `record_series`. Only
+    /// buckets containing events are returned.
+    pub fn event_counts(
+        &self,
+        event_name: &str,
+        bucket_us: u64,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+    ) -> Result<Vec<CountEntry>> {
+        let mut stmt = self.db.prepare(
+            "
+SELECT (timestamp / ?2) * ?2 AS bucket, COUNT(*)
+FROM events
+WHERE event_name = ?1 AND (?3 IS NULL OR timestamp >= ?3) AND (?4 IS NULL OR timestamp < ?4)
+GROUP BY bucket
+ORDER BY bucket ASC",
+        )?;
+        let entries = stmt
+            .query_map(
+                rusqlite::params![event_name, bucket_us.max(1), from, to],
+                |row| {
+                    Ok(CountEntry {
+                        timestamp: row.get(0)?,
+                        count: row.get(1)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Number of events per value of the payload `field`, most frequent
+    /// first.
+    pub fn event_breakdown(
+        &self,
+        event_name: &str,
+        field: &str,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+    ) -> Result<Vec<BreakdownEntry>> {
+        let mut stmt = self.db.prepare(
+            "
+SELECT
+CASE json_type(payload, ?2)
+    WHEN 'true' THEN 'true'
+    WHEN 'false' THEN 'false'
+    ELSE IFNULL(CAST(json_extract(payload, ?2) AS TEXT), '')
+END AS value,
+COUNT(*) AS count
+FROM events
+WHERE event_name = ?1 AND (?3 IS NULL OR timestamp >= ?3) AND (?4 IS NULL OR timestamp < ?4)
+GROUP BY value
+ORDER BY count DESC, value ASC",
+        )?;
+        let entries = stmt
+            .query_map(
+                rusqlite::params![event_name, field_path(field)?, from, to],
+                |row| {
+                    Ok(BreakdownEntry {
+                        value: row.get(0)?,
+                        count: row.get(1)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+}
+
+impl Kodama {
+    pub fn add_event(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        event_name: &str,
+        timestamp: Option<Timestamp>,
+        payload: &serde_json::Value,
+    ) -> Result<()> {
+        let service = self.get_or_provision_service(project_name, service_name)?;
+        service.borrow().add_event(timestamp, event_name, payload)?;
+        Ok(())
+    }
+
+    pub fn event_list(&mut self, project_name: &str, service_name: &str) -> Result<Vec<ListEvent>> {
+        let service = self.get_service(project_name, service_name)?;
+        let events = service.borrow().event_list()?;
+        Ok(events)
+    }
+
+    pub fn event_counts(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        event_name: &str,
+        bucket_us: u64,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+    ) -> Result<Vec<CountEntry>> {
+        let service = self.get_service(project_name, service_name)?;
+        let entries = service
+            .borrow()
+            .event_counts(event_name, bucket_us, from, to)?;
+        Ok(entries)
+    }
+
+    pub fn event_breakdown(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        event_name: &str,
+        field: &str,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+    ) -> Result<Vec<BreakdownEntry>> {
+        let service = self.get_service(project_name, service_name)?;
+        let entries = service
+            .borrow()
+            .event_breakdown(event_name, field, from, to)?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn counts_and_breakdown() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(crate::migration::events_sql()).unwrap();
+        let service = Service {
+            id: 1,
+            db,
+            batch: None,
+        };
+
+        for (microseconds, name, payload) in [
+            (10, "login", json!({"user": {"country": "SE"}, "ok": true})),
+            (20, "login", json!({"user": {"country": "SE"}, "ok": false})),
+            (110, "login", json!({"user": {"country": "JP"}, "ok": true})),
+            (120, "login", json!(null)),
+            (130, "logout", json!({"user": {"country": "SE"}})),
+        ] {
+            service
+                .add_event(Some(Timestamp { microseconds }), name, &payload)
+                .unwrap();
+        }
+
+        let events = service.event_list().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].name.as_str(), events[0].count), ("login", 4));
+        assert_eq!(events[0].last.microseconds, 120);
+
+        let counts = service
+            .event_counts("login", 100, None, None)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.timestamp.microseconds, entry.count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [(0, 2), (100, 2)]);
+
+        let breakdown = |field| {
+            service
+                .event_breakdown("login", field, Some(&Timestamp { microseconds: 10 }), None)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.value, entry.count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            breakdown("user.country"),
+            [
+                ("SE".to_string(), 2),
+                ("".to_string(), 1),
+                ("JP".to_string(), 1)
+            ]
+        );
+        assert_eq!(
+            breakdown("ok"),
+            [
+                ("true".to_string(), 2),
+                ("".to_string(), 1),
+                ("false".to_string(), 1)
+            ]
+        );
+        assert!(service
+            .event_breakdown("login", "user..country", None, None)
+            .is_err());
+        assert!(field_path("user.\"country").is_err());
+    }
+}
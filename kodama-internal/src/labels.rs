@@ -0,0 +1,148 @@
+use crate::{ApiError, Result};
+use kodama_api::Labels;
+use rusqlite::types::Value;
+
+/// Label keys are embedded in JSON paths, so they are limited to ASCII
+/// letters, digits, `_`, `-` and `.`.
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// JSON path of the label `key` within a `labels` column.
+fn label_path(key: &str) -> Result<String> {
+    if !is_valid_key(key) {
+        return Err(ApiError::InvalidLabel(key.to_string()).into());
+    }
+    Ok(format!("$.\"{}\"", key))
+}
+
+/// Labels as stored in the `labels` column of record and metric tables.
+pub(crate) fn to_json(labels: &Labels) -> Result<String> {
+    if let Some(key) = labels.keys().find(|key| !is_valid_key(key)) {
+        return Err(ApiError::InvalidLabel(key.clone()).into());
+    }
+    fields_to_json(labels)
+}
+
+/// Log fields as stored in the `fields` column. They are never queried by
+/// key, so any key is accepted.
+pub(crate) fn fields_to_json(fields: &Labels) -> Result<String> {
+    serde_json::to_string(fields).map_err(|err| ApiError::InvalidLabel(err.to_string()).into())
+}
+
+pub(crate) fn from_json(json: &str) -> Labels {
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+/// Label filter and grouping of a record or metric query, with parameters
+/// numbered from the `first_param` given to `LabelQuery::new`.
+pub(crate) struct LabelQuery {
+    /// Expression rows are grouped by, `group_by` unless grouping by a label
+    pub group: String,
+    /// `AND` conditions matching rows carrying every filtered label
+    pub filter: String,
+    pub params: Vec<Value>,
+}
+
+impl LabelQuery {
+    pub fn new(filter: &Labels, group_by_label: Option<&str>, first_param: usize) -> Result<Self> {
+        let mut query = Self {
+            group: "group_by".to_string(),
+            filter: String::new(),
+            params: Vec::new(),
+        };
+
+        if let Some(key) = group_by_label {
+            query.params.push(Value::Text(label_path(key)?));
+            // rows without the label are grouped together under ""
+            query.group = format!("IFNULL(json_extract(labels, ?{}), '')", first_param);
+        }
+
+        for (key, value) in filter {
+            let param = first_param + query.params.len();
+            query.params.push(Value::Text(label_path(key)?));
+            query.params.push(Value::Text(value.clone()));
+            query.filter.push_str(&format!(
+                " AND json_extract(labels, ?{}) = ?{}",
+                param,
+                param + 1
+            ));
+        }
+
+        Ok(query)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_and_group() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch("CREATE TABLE samples (group_by TEXT NOT NULL, labels TEXT NOT NULL);")
+            .unwrap();
+        for (group_by, labels) in [
+            ("a", [("host", "web-1"), ("region", "eu")]),
+            ("a", [("host", "web-2"), ("region", "eu")]),
+            ("b", [("host", "web-1"), ("region", "us")]),
+        ] {
+            let labels = labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            db.execute(
+                "INSERT INTO samples (group_by, labels) VALUES (?1, ?2)",
+                rusqlite::params![group_by, to_json(&labels).unwrap()],
+            )
+            .unwrap();
+        }
+        db.execute(
+            "INSERT INTO samples (group_by, labels) VALUES ('c', '{}')",
+            [],
+        )
+        .unwrap();
+
+        let filter = Labels::from([("region".to_string(), "eu".to_string())]);
+        let query = LabelQuery::new(&filter, Some("host"), 2).unwrap();
+        let mut stmt = db
+            .prepare(&format!(
+                "SELECT {0}, COUNT(*) FROM samples WHERE group_by = ?1{1} GROUP BY {0} ORDER BY 1",
+                query.group, query.filter
+            ))
+            .unwrap();
+        let mut params = vec![Value::Text("a".to_string())];
+        params.extend(query.params);
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, [("web-1".to_string(), 1), ("web-2".to_string(), 1)]);
+
+        let query = LabelQuery::new(&Labels::new(), Some("zone"), 1).unwrap();
+        let groups: Vec<String> = db
+            .prepare(&format!("SELECT DISTINCT {} FROM samples", query.group))
+            .unwrap()
+            .query_map(rusqlite::params_from_iter(query.params), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(groups, [""]);
+
+        let invalid = Labels::from([("host\"".to_string(), "web-1".to_string())]);
+        assert!(to_json(&invalid).is_err());
+        assert!(LabelQuery::new(&invalid, None, 1).is_err());
+        assert!(LabelQuery::new(&Labels::new(), Some(""), 1).is_err());
+        assert_eq!(from_json("{\"host\":\"web-1\"}")["host"], "web-1");
+    }
+}
@@ -1,56 +1,276 @@
-use kodama_api::Timestamp;
+use batch::WriteBatch;
+use kodama_api::{Labels, Timestamp};
+use labels::LabelQuery;
+use metric::{ListMetric, MetricValue};
+use migration::MigrationStatus;
 use project::ListProject;
-use record::{DataEntry, ListRecord};
+use record::{percentile_rank, DataEntry, ListRecord, Percentile, SeriesEntry};
+use retention::{Policies, RetentionPolicy};
+use rusqlite::ToSql;
 use service::ListService;
-use std::{cell::RefCell, collections::HashMap, path::PathBuf, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, path::PathBuf, rc::Rc, time::Instant};
 
+#[macro_use]
+mod macros;
+
+pub mod alert;
+pub mod anomaly;
+mod batch;
+pub use batch::WriteBatching;
 mod error;
 pub use error::*;
+pub mod event;
+mod labels;
+pub mod log;
 pub mod metric;
+pub mod migration;
+pub mod notifier;
 pub mod project;
+mod provision;
+pub use provision::*;
 pub mod record;
+pub mod retention;
+mod rollup;
 pub mod service;
+mod sketch;
+#[cfg(test)]
+mod testing;
 
 struct Service {
     id: i64,
     db: rusqlite::Connection,
+    batch: Option<WriteBatch>,
 }
 
 impl Service {
-    pub fn open(path: &String, service_id: i64) -> Result<Self> {
+    pub fn open(path: &String, service_id: i64, batching: Option<WriteBatching>) -> Result<Self> {
+        let mut db = Self::connect(path, service_id)?;
+        migration::service_migrations(&db)?.apply(&mut db)?;
+
+        Ok(Self {
+            id: service_id,
+            db,
+            batch: batching.map(WriteBatch::new),
+        })
+    }
+
+    fn connect(path: &String, service_id: i64) -> Result<rusqlite::Connection> {
         let path = PathBuf::from(path).join(format!("service-{}.db", service_id));
         let db = rusqlite::Connection::open(path)?;
+        Self::configure(&db)?;
+        Ok(db)
+    }
+
+    /// Open and migrate the database of a service for background work, or
+    /// `None` if the service never stored anything and has no database yet.
+    fn open_existing(path: &String, service_id: i64) -> Result<Option<Self>> {
+        let Some(mut db) = Self::connect_existing(path, service_id)? else {
+            return Ok(None);
+        };
+        migration::service_migrations(&db)?.apply(&mut db)?;
+
+        Ok(Some(Self {
+            id: service_id,
+            db,
+            batch: None,
+        }))
+    }
+
+    /// Like `connect`, but without creating a missing database.
+    fn connect_existing(path: &String, service_id: i64) -> Result<Option<rusqlite::Connection>> {
+        let path = PathBuf::from(path).join(format!("service-{}.db", service_id));
+        let flags =
+            rusqlite::OpenFlags::default().difference(rusqlite::OpenFlags::SQLITE_OPEN_CREATE);
+        let db = match rusqlite::Connection::open_with_flags(path, flags) {
+            Ok(db) => db,
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == rusqlite::ErrorCode::CannotOpen =>
+            {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Self::configure(&db)?;
+        Ok(Some(db))
+    }
+
+    fn configure(db: &rusqlite::Connection) -> Result<()> {
         // enable foreign key constraints
         db.execute_batch("PRAGMA foreign_keys = ON;")?;
-
-        Ok(Self { id: service_id, db })
+        // several threads may write to the same database
+        db.busy_timeout(BUSY_TIMEOUT)?;
+        enable_wal(db)?;
+        db.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(())
     }
 
-    /// Create a table to store record data
+    /// Create a table to store record data. The registry row in `kodama.db`
+    /// is already committed, so an open write batch is committed first
+    /// rather than leaving the table in it.
     pub fn define_record(&self, record_id: i64) -> Result<()> {
         tracing::debug!("define record {}", record_id);
-        self.db.execute_batch(&format!(
-            "CREATE TABLE IF NOT EXISTS record_{} (
-            timestamp INTEGER PRIMARY KEY,
-            group_by TEXT NOT NULL,
-            execution_time_us INTEGER NOT NULL,
-            error INTEGER DEFAULT 0
-        );",
+        self.flush()?;
+        self.db
+            .execute_batch(&migration::record_table_sql(record_id))?;
+        self.db
+            .execute_batch(&migration::rollup_table_sql(record_id))?;
+
+        Ok(())
+    }
+
+    /// Drop the tables and rollup state of a record.
+    pub fn drop_record(&self, record_id: i64) -> Result<()> {
+        tracing::debug!("drop record {}", record_id);
+        self.flush()?;
+        let transaction = self.db.unchecked_transaction()?;
+        transaction.execute_batch(&format!(
+            "DROP TABLE IF EXISTS record_{0};
+            DROP TABLE IF EXISTS rollup_{0};",
             record_id
         ))?;
+        transaction.execute(
+            "DELETE FROM rollup_state WHERE record_id = ?1",
+            rusqlite::params![record_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM rollup_rows WHERE record_id = ?1",
+            rusqlite::params![record_id],
+        )?;
+        transaction.commit()?;
+        // cached inserts refer to the dropped table
+        self.db.flush_prepared_statement_cache();
+        Ok(())
+    }
+
+    /// Delete the records before `before`, or all of them, returning the
+    /// number of deleted records. Rollup buckets ending by `before` are
+    /// deleted too, so the range does not reappear from rollups. Buckets
+    /// spanning `before` are kept, as the records after it may only be left
+    /// in them.
+    pub fn truncate_record(&self, record_id: i64, before: Option<&Timestamp>) -> Result<u64> {
+        self.flush()?;
+        let transaction = self.db.unchecked_transaction()?;
+        let deleted = transaction.execute(
+            &format!(
+                "DELETE FROM record_{} WHERE ?1 IS NULL OR timestamp < ?1",
+                record_id
+            ),
+            rusqlite::params![before],
+        )?;
+        transaction.execute(
+            &format!(
+                "DELETE FROM rollup_{} WHERE ?1 IS NULL OR timestamp + resolution <= ?1",
+                record_id
+            ),
+            rusqlite::params![before],
+        )?;
+        if before.is_none() {
+            transaction.execute(
+                "DELETE FROM rollup_state WHERE record_id = ?1",
+                rusqlite::params![record_id],
+            )?;
+            transaction.execute(
+                "DELETE FROM rollup_rows WHERE record_id = ?1",
+                rusqlite::params![record_id],
+            )?;
+        } else {
+            rollup::clamp_rollup_rows(&transaction, record_id)?;
+        }
+        transaction.commit()?;
+        Ok(deleted as u64)
+    }
+
+    /// Delete the records and rollups of a single group_by, returning the
+    /// number of deleted records.
+    pub fn delete_group(&self, record_id: i64, group_by: &str) -> Result<u64> {
+        self.flush()?;
+        let transaction = self.db.unchecked_transaction()?;
+        let deleted = transaction.execute(
+            &format!("DELETE FROM record_{} WHERE group_by = ?1", record_id),
+            rusqlite::params![group_by],
+        )?;
+        transaction.execute(
+            &format!("DELETE FROM rollup_{} WHERE group_by = ?1", record_id),
+            rusqlite::params![group_by],
+        )?;
+        rollup::clamp_rollup_rows(&transaction, record_id)?;
+        transaction.commit()?;
+        Ok(deleted as u64)
+    }
+
+    /// Create a table to store metric values
+    pub fn define_metric(&self, metric_id: i64) -> Result<()> {
+        tracing::debug!("define metric {}", metric_id);
+        self.flush()?;
+        self.db
+            .execute_batch(&migration::metric_table_sql(metric_id))?;
 
         Ok(())
     }
 
+    pub fn add_metric(
+        &self,
+        metric_id: i64,
+        timestamp: Option<Timestamp>,
+        value: f64,
+        labels: &Labels,
+    ) -> Result<()> {
+        let labels = labels::to_json(labels)?;
+        let mut stmt = self.db.prepare_cached(&format!(
+            "INSERT INTO metric_{} (timestamp, value, labels) VALUES (?1, ?2, ?3)",
+            metric_id
+        ))?;
+
+        let timestamp = if let Some(timestamp) = timestamp {
+            timestamp
+        } else if let Some(timestamp) = Timestamp::now() {
+            timestamp
+        } else {
+            return Err(ApiError::InvalidTimestamp.into());
+        };
+
+        self.begin_write()?;
+        stmt.execute(rusqlite::params![timestamp, value, labels])?;
+        self.end_write()
+    }
+
+    /// Metric values carrying every label in `filter`.
+    pub fn metric_values(&self, metric_id: i64, filter: &Labels) -> Result<Vec<MetricValue>> {
+        let labels = LabelQuery::new(filter, None, 1)?;
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT timestamp, value, labels FROM metric_{} WHERE 1 = 1{} ORDER BY timestamp ASC",
+            metric_id, labels.filter
+        ))?;
+        let values = stmt
+            .query_map(rusqlite::params_from_iter(&labels.params), |row| {
+                Ok(MetricValue {
+                    timestamp: row.get(0)?,
+                    value: row.get(1)?,
+                    labels: labels::from_json(&row.get::<_, String>(2)?),
+                })
+            })?
+            .inspect(|x| {
+                if let Err(e) = x {
+                    tracing::error!("error: {:?}", e);
+                }
+            })
+            .filter_map(|x| x.ok())
+            .collect::<Vec<_>>();
+        Ok(values)
+    }
+
     pub fn add_record(
         &self,
         record_id: i64,
         timestamp: Option<Timestamp>,
         group_by: &str,
         execution_time: u64,
+        error: bool,
+        labels: &Labels,
     ) -> Result<()> {
-        let mut stmt = self.db.prepare(&format!(
-            "INSERT INTO record_{} (timestamp, group_by, execution_time_us) VALUES (?1, ?2, ?3)",
+        let labels = labels::to_json(labels)?;
+        let mut stmt = self.db.prepare_cached(&format!(
+            "INSERT INTO record_{} (timestamp, group_by, execution_time_us, error, labels) VALUES (?1, ?2, ?3, ?4, ?5)",
             record_id
         ))?;
 
@@ -62,27 +282,100 @@ impl Service {
             return Err(ApiError::InvalidTimestamp.into());
         };
 
-        stmt.execute(rusqlite::params![timestamp, group_by, execution_time])?;
-        Ok(())
+        self.begin_write()?;
+        stmt.execute(rusqlite::params![
+            timestamp,
+            group_by,
+            execution_time,
+            error,
+            labels
+        ])?;
+        self.end_write()
+    }
+
+    fn begin_write(&self) -> Result<()> {
+        match &self.batch {
+            Some(batch) => batch.begin(&self.db),
+            None => Ok(()),
+        }
+    }
+
+    fn end_write(&self) -> Result<()> {
+        match &self.batch {
+            Some(batch) => batch.written(&self.db),
+            None => Ok(()),
+        }
     }
 
-    pub fn record_entries(&self, record_id: i64) -> Result<Vec<DataEntry>> {
+    /// Commit the open write batch, if any.
+    pub fn flush(&self) -> Result<()> {
+        match &self.batch {
+            Some(batch) => batch.commit(&self.db),
+            None => Ok(()),
+        }
+    }
+
+    /// Aggregate record data per group_by, or per value of the label
+    /// `group_by_label`, over the records carrying every label in `filter`.
+    /// `from` is inclusive and `to` is exclusive, a missing bound leaves that
+    /// side of the range open.
+    ///
+    /// Percentiles are computed with the nearest-rank method in a single
+    /// ordered scan over the record table, only keeping the ranks of interest
+    /// in memory. Ranges reaching back before the oldest raw record are read
+    /// from rollups instead, with approximate percentiles. Rollups do not
+    /// keep labels, so queries by label reaching back that far are refused.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_entries(
+        &self,
+        record_id: i64,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+        percentiles: &[f64],
+        filter: &Labels,
+        group_by_label: Option<&str>,
+    ) -> Result<Vec<DataEntry>> {
+        if let Some(percentile) = percentiles.iter().find(|p| !(**p > 0.0 && **p <= 100.0)) {
+            return Err(ApiError::InvalidPercentile(*percentile).into());
+        }
+
+        let labels = LabelQuery::new(filter, group_by_label, 3)?;
+        let mut params: Vec<&dyn ToSql> = vec![&from, &to];
+        params.extend(labels.params.iter().map(|param| param as &dyn ToSql));
+
+        if let Some((cutoff, watermarks)) = self.rollup_cutoff(record_id)? {
+            let from = from.map_or(0, |from| from.microseconds);
+            if from < cutoff && !labels.is_empty() {
+                return Err(ApiError::InvalidLabelRange(cutoff).into());
+            } else if from < cutoff {
+                return self.record_entries_with_rollups(
+                    record_id,
+                    from,
+                    to,
+                    cutoff,
+                    &watermarks,
+                    percentiles,
+                );
+            }
+        }
+
         let mut stmt = self.db.prepare(&format!(
             "
 SELECT 
-group_by, 
+{1}, 
 COUNT(*), 
 SUM(execution_time_us), 
 AVG(execution_time_us), 
 MAX(execution_time_us), 
 MIN(execution_time_us),
 COUNT(CASE WHEN error > 0 THEN 1 ELSE NULL END) AS error_count
-FROM record_{}
-GROUP BY group_by",
-            record_id
+FROM record_{0}
+WHERE (?1 IS NULL OR timestamp >= ?1) AND (?2 IS NULL OR timestamp < ?2){2}
+GROUP BY 1",
+            record_id, labels.group, labels.filter
         ))?;
         let mut rows = stmt
-            .query_map(rusqlite::params![], |row| {
+            .query_map(params.as_slice(), |row| {
                 let avg: f64 = row.get(3)?;
                 let avg_rounded = avg.round() as u64;
                 Ok(DataEntry {
@@ -95,6 +388,13 @@ GROUP BY group_by",
                     min: row.get(5)?,
                     p50: 0,
                     p95: 0,
+                    percentiles: percentiles
+                        .iter()
+                        .map(|percentile| Percentile {
+                            percentile: *percentile,
+                            value: 0,
+                        })
+                        .collect(),
                 })
             })?
             .inspect(|x| {
@@ -105,34 +405,259 @@ GROUP BY group_by",
             .filter_map(|x| x.ok())
             .collect::<Vec<_>>();
 
-        for row in rows.iter_mut() {
-            let percentile_50 = row.count * 50 / 100;
-            let percentile_95 = row.count * 95 / 100;
+        let groups = rows
+            .iter()
+            .enumerate()
+            .map(|(index, row)| (row.group_by.clone(), index))
+            .collect::<HashMap<_, _>>();
 
-            let p50 = self
-            .db
-            .prepare(&format!("SELECT execution_time_us FROM record_{} WHERE group_by = ?1 ORDER BY execution_time_us ASC LIMIT 1 OFFSET ?2", record_id))?
-            .query_row(rusqlite::params![row.group_by, percentile_50], |row| row.get(0))?;
-            let p95 = self
-            .db
-            .prepare(&format!("SELECT execution_time_us FROM record_{} WHERE group_by = ?1 ORDER BY execution_time_us ASC LIMIT 1 OFFSET ?2", record_id))?
-            .query_row(rusqlite::params![row.group_by, percentile_95], |row| row.get(0))?;
+        let mut stmt = self.db.prepare(&format!(
+            "
+SELECT {1} AS grouping, execution_time_us
+FROM record_{0}
+WHERE (?1 IS NULL OR timestamp >= ?1) AND (?2 IS NULL OR timestamp < ?2){2}
+ORDER BY grouping, execution_time_us ASC",
+            record_id, labels.group, labels.filter
+        ))?;
+        let mut values = stmt.query(params.as_slice())?;
+
+        // (rank, target) pairs of the current group sorted by rank, where
+        // target 0 and 1 are p50 and p95 and the rest index `percentiles`
+        let mut ranks = Vec::new();
+        let mut current: Option<(String, usize)> = None;
+        let mut position = 0;
+        while let Some(value) = values.next()? {
+            let group_by: String = value.get(0)?;
+            if current
+                .as_ref()
+                .map(|(name, _)| name != &group_by)
+                .unwrap_or(true)
+            {
+                let Some(index) = groups.get(&group_by) else {
+                    current = None;
+                    continue;
+                };
+
+                let count = rows[*index].count as usize;
+                ranks.clear();
+                ranks.push((percentile_rank(count, 50.0), 0));
+                ranks.push((percentile_rank(count, 95.0), 1));
+                for (target, percentile) in percentiles.iter().enumerate() {
+                    ranks.push((percentile_rank(count, *percentile), target + 2));
+                }
+                ranks.sort_unstable();
+                ranks.reverse();
+
+                current = Some((group_by, *index));
+                position = 0;
+            }
 
-            row.p50 = p50;
-            row.p95 = p95;
+            let Some((_, index)) = current.as_ref() else {
+                continue;
+            };
+
+            let execution_time: u64 = value.get(1)?;
+            let row = &mut rows[*index];
+            while ranks.last().is_some_and(|(rank, _)| *rank == position) {
+                let (_, target) = ranks.pop().unwrap();
+                match target {
+                    0 => row.p50 = execution_time,
+                    1 => row.p95 = execution_time,
+                    _ => row.percentiles[target - 2].value = execution_time,
+                }
+            }
+            position += 1;
         }
 
         Ok(rows)
     }
+
+    /// Aggregate record data into fixed-width time buckets. Buckets are
+    /// aligned to multiples of `bucket_us` since the unix epoch and only
+    /// buckets containing records are returned. Like `record_entries`, older
+    /// buckets are built from rollups and refused when filtering by label.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_series(
+        &self,
+        record_id: i64,
+        bucket_us: u64,
+        group_by: Option<&str>,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+        filter: &Labels,
+    ) -> Result<Vec<SeriesEntry>> {
+        let bucket = bucket_us.max(1);
+        let labels = LabelQuery::new(filter, None, 5)?;
+        let mut params: Vec<&dyn ToSql> = vec![&bucket, &group_by, &from, &to];
+        params.extend(labels.params.iter().map(|param| param as &dyn ToSql));
+
+        if let Some((cutoff, watermarks)) = self.rollup_cutoff(record_id)? {
+            let from = from.map_or(0, |from| from.microseconds);
+            if from < cutoff && !labels.is_empty() {
+                return Err(ApiError::InvalidLabelRange(cutoff).into());
+            } else if from < cutoff {
+                return self.record_series_with_rollups(
+                    record_id,
+                    bucket,
+                    group_by,
+                    from,
+                    to,
+                    cutoff,
+                    &watermarks,
+                );
+            }
+        }
+
+        let mut stmt = self.db.prepare(&format!(
+            "
+SELECT
+(timestamp / ?1) * ?1 AS bucket,
+execution_time_us,
+error
+FROM record_{}
+WHERE (?2 IS NULL OR group_by = ?2) AND (?3 IS NULL OR timestamp >= ?3) AND (?4 IS NULL OR timestamp < ?4){}
+ORDER BY bucket ASC, execution_time_us ASC",
+            record_id, labels.filter
+        ))?;
+        let mut rows = stmt.query(params.as_slice())?;
+
+        let mut entries = Vec::new();
+        let mut bucket: Option<u64> = None;
+        let mut values = Vec::new();
+        let mut errors = 0;
+        while let Some(row) = rows.next()? {
+            let row_bucket: u64 = row.get(0)?;
+            if let Some(bucket) = bucket.filter(|bucket| *bucket != row_bucket) {
+                entries.push(SeriesEntry::from_sorted(bucket, &values, errors));
+                values.clear();
+                errors = 0;
+            }
+
+            bucket = Some(row_bucket);
+            values.push(row.get::<_, u64>(1)?);
+            if row.get::<_, i64>(2)? > 0 {
+                errors += 1;
+            }
+        }
+
+        if let Some(bucket) = bucket {
+            entries.push(SeriesEntry::from_sorted(bucket, &values, errors));
+        }
+
+        Ok(entries)
+    }
+}
+
+impl Service {
+    /// Whether `table` exists. Tables created by migrations may be missing
+    /// from databases only opened for pruning, which are not migrated.
+    fn has_table(&self, table: &str) -> Result<bool> {
+        let exists = self.db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            rusqlite::params![table],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    /// Delete rows older than `before` from a record or metric table, in
+    /// chunks so concurrent writers are not blocked for long. The rolled up
+    /// rowid of `record_id` is clamped along with every chunk.
+    fn prune_table(&self, table: &str, before: i64, record_id: Option<i64>) -> Result<u64> {
+        const CHUNK_SIZE: usize = 10000;
+
+        let mut stmt = self.db.prepare(&format!(
+            "DELETE FROM {0} WHERE rowid IN (SELECT rowid FROM {0} WHERE timestamp < ?1 LIMIT ?2)",
+            table
+        ))?;
+        let mut deleted = 0;
+        loop {
+            let transaction = self.db.unchecked_transaction()?;
+            let changes = stmt.execute(rusqlite::params![before, CHUNK_SIZE])?;
+            if let Some(record_id) = record_id {
+                rollup::clamp_rollup_rows(&transaction, record_id)?;
+            }
+            transaction.commit()?;
+            deleted += changes as u64;
+            if changes < CHUNK_SIZE {
+                return Ok(deleted);
+            }
+        }
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            tracing::error!("unable to flush service {}: {:?}", self.id, err);
+        }
+    }
 }
 
 type ServiceRef = Rc<RefCell<Service>>;
 
+/// Delete the rows of a service and everything referring to it from
+/// `kodama.db`.
+fn delete_service_rows(db: &rusqlite::Connection, service_id: i64) -> Result<()> {
+    for sql in [
+        "DELETE FROM alert_routes WHERE alert_id IN (SELECT alert_id FROM alert_rules WHERE service_id = ?1)",
+        "DELETE FROM alert_history WHERE alert_id IN (SELECT alert_id FROM alert_rules WHERE service_id = ?1)",
+        "DELETE FROM alert_rules WHERE service_id = ?1",
+        "DELETE FROM retention_policies WHERE service_id = ?1",
+        "DELETE FROM records WHERE service_id = ?1",
+        "DELETE FROM metrics WHERE service_id = ?1",
+        "DELETE FROM services WHERE service_id = ?1",
+    ] {
+        db.execute(sql, rusqlite::params![service_id])?;
+    }
+    Ok(())
+}
+
+/// File in the database directory a running server holds a shared lock on,
+/// see `Kodama::server_lock`.
+const SERVER_LOCK: &str = "server.lock";
+
+/// Shared lock of a running server on its database, released when dropped.
+pub struct ServerLock {
+    _file: std::fs::File,
+}
+
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Each record and metric table has its own insert statement.
+const STATEMENT_CACHE_CAPACITY: usize = 128;
+
+/// Use write-ahead logging, so readers don't block the writer and a commit
+/// no longer needs to sync the database file itself.
+fn enable_wal(db: &rusqlite::Connection) -> Result<()> {
+    let mode: String = db.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    if !mode.eq_ignore_ascii_case("wal") {
+        tracing::warn!("unable to enable WAL journaling, using {}", mode);
+    }
+    db.pragma_update(None, "synchronous", "NORMAL")?;
+    Ok(())
+}
+
+/// Alert and notifier names must be non-empty and may not contain `/` or
+/// control characters.
+fn is_valid_name(name: &str) -> bool {
+    !name.trim().is_empty() && !name.chars().any(|c| c == '/' || c.is_control())
+}
+
+fn is_constraint_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(err, _) if err.code == rusqlite::ErrorCode::ConstraintViolation
+    )
+}
+
 pub struct Kodama {
     db: rusqlite::Connection,
     database_path: String,
     services_by_ps: HashMap<(String, String), ServiceRef>,
     services_by_id: HashMap<i64, ServiceRef>,
+    auto_provision: Option<AutoProvision>,
+    write_batching: Option<WriteBatching>,
 }
 
 impl Kodama {
@@ -144,26 +669,183 @@ impl Kodama {
         let db = rusqlite::Connection::open(path)?;
         // enable foreign key constraints
         db.execute_batch("PRAGMA foreign_keys = ON;")?;
+        // several threads may write to the same database
+        db.busy_timeout(BUSY_TIMEOUT)?;
+        enable_wal(&db)?;
 
         Ok(Self {
             db,
             database_path,
             services_by_ps: HashMap::new(),
             services_by_id: HashMap::new(),
+            auto_provision: None,
+            write_batching: None,
         })
     }
 
-    pub fn initialize(self) -> Result<Self> {
-        self.db
-            .execute_batch(include_str!("../../schema/schema.sql"))?;
-        Ok(self)
+    /// Lock the database for a server. Servers cache services by name, so
+    /// renaming or deleting projects and services is refused while a server
+    /// holds the lock.
+    pub fn server_lock(&self) -> Result<ServerLock> {
+        let file = self.open_server_lock()?;
+        file.lock_shared()?;
+        Ok(ServerLock { _file: file })
+    }
+
+    /// Lock the database against servers, failing if one is running.
+    fn exclusive_lock(&self) -> Result<std::fs::File> {
+        let file = self.open_server_lock()?;
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(std::fs::TryLockError::WouldBlock) => Err(ApiError::DatabaseInUse.into()),
+            Err(std::fs::TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+
+    fn open_server_lock(&self) -> Result<std::fs::File> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(PathBuf::from(&self.database_path).join(SERVER_LOCK))?;
+        Ok(file)
+    }
+
+    /// Create unknown projects and services when data is pushed to them.
+    pub fn with_auto_provision(mut self, auto_provision: AutoProvision) -> Self {
+        self.auto_provision = Some(auto_provision);
+        self
+    }
+
+    /// Batch record and metric inserts per service, see `WriteBatching`.
+    /// Call `flush_expired` regularly and `flush` before dropping the
+    /// instance to commit them in time.
+    pub fn with_write_batching(mut self, write_batching: WriteBatching) -> Self {
+        self.write_batching = Some(write_batching);
+        self
+    }
+
+    /// Commit all open write batches.
+    pub fn flush(&mut self) -> Result<()> {
+        for service in self.services_by_id.values() {
+            service.borrow().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Commit the write batches whose flush interval has passed.
+    pub fn flush_expired(&mut self) -> Result<()> {
+        let now = Instant::now();
+        for service in self.services_by_id.values() {
+            let service = service.borrow();
+            let expired = service
+                .batch
+                .as_ref()
+                .and_then(|batch| batch.deadline())
+                .is_some_and(|deadline| deadline <= now);
+            if expired {
+                service.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The earliest time `flush_expired` has work to do.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.services_by_id
+            .values()
+            .filter_map(|service| service.borrow().batch.as_ref()?.deadline())
+            .min()
+    }
+
+    /// List every migration of `kodama.db` and all service databases,
+    /// together with whether it has been applied.
+    pub fn migration_status(&self) -> Result<Vec<MigrationStatus>> {
+        let mut status = Vec::new();
+        let migrations = migration::kodama_migrations();
+        let pending = migrations.pending(&self.db)?;
+        for version in migrations.versions() {
+            status.push(MigrationStatus {
+                database: "kodama.db".to_string(),
+                version: version.to_string(),
+                applied: !pending.iter().any(|x| x == version),
+            });
+        }
+
+        // services without a database get migrated once they are opened
+        for service_id in self.service_ids()? {
+            let Some(db) = Service::connect_existing(&self.database_path, service_id)? else {
+                continue;
+            };
+            let migrations = migration::service_migrations(&db)?;
+            let pending = migrations.pending(&db)?;
+            for version in migrations.versions() {
+                status.push(MigrationStatus {
+                    database: format!("service-{}.db", service_id),
+                    version: version.to_string(),
+                    applied: !pending.iter().any(|x| x == version),
+                });
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Apply all pending migrations to `kodama.db` and all service databases
+    /// and return the migrations that were applied.
+    pub fn migrate(&mut self) -> Result<Vec<MigrationStatus>> {
+        let mut applied = Vec::new();
+        for version in migration::kodama_migrations().apply(&mut self.db)? {
+            applied.push(MigrationStatus {
+                database: "kodama.db".to_string(),
+                version,
+                applied: true,
+            });
+        }
+
+        for service_id in self.service_ids()? {
+            let Some(mut db) = Service::connect_existing(&self.database_path, service_id)? else {
+                continue;
+            };
+            for version in migration::service_migrations(&db)?.apply(&mut db)? {
+                applied.push(MigrationStatus {
+                    database: format!("service-{}.db", service_id),
+                    version,
+                    applied: true,
+                });
+            }
+        }
+
+        Ok(applied)
+    }
+
+    fn service_ids(&self) -> Result<Vec<i64>> {
+        let has_services: bool = self.db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'services')",
+            rusqlite::params![],
+            |row| row.get(0),
+        )?;
+        if !has_services {
+            return Ok(Vec::new());
+        }
+
+        let mut stmt = self.db.prepare("SELECT service_id FROM services")?;
+        let service_ids = stmt
+            .query_map(rusqlite::params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(service_ids)
     }
 
     pub fn create_project(&self, project_name: &str, description: &str) -> Result<i64> {
         let mut stmt = self
             .db
             .prepare("INSERT INTO projects (project_name, description) VALUES (?1, ?2)")?;
-        let project_id = stmt.insert(rusqlite::params![project_name, description])?;
+        let project_id = stmt
+            .insert(rusqlite::params![project_name, description])
+            .map_err(|err| match is_constraint_violation(&err) {
+                true => ApiError::ProjectAlreadyExists(project_name.to_string()).into(),
+                false => Error::from(err),
+            })?;
         Ok(project_id)
     }
 
@@ -190,11 +872,74 @@ impl Kodama {
             .db
             .prepare("SELECT project_id FROM projects WHERE project_name = ?1")?;
         let mut rows = stmt.query(rusqlite::params![project_name])?;
-        let row = rows.next()?.ok_or(ApiError::ProjectNotFound)?;
+        let row = rows
+            .next()?
+            .ok_or_else(|| ApiError::ProjectNotFound(project_name.to_string()))?;
         let project_id = row.get(0)?;
         Ok(project_id)
     }
 
+    pub fn rename_project(&mut self, project_name: &str, new_name: &str) -> Result<()> {
+        let _lock = self.exclusive_lock()?;
+        let project_id = self.get_project_id(project_name)?;
+        self.db
+            .execute(
+                "UPDATE projects SET project_name = ?2 WHERE project_id = ?1",
+                rusqlite::params![project_id, new_name],
+            )
+            .map_err(|err| match is_constraint_violation(&err) {
+                true => ApiError::ProjectAlreadyExists(new_name.to_string()).into(),
+                false => Error::from(err),
+            })?;
+        self.services_by_ps
+            .retain(|(project, _), _| project != project_name);
+        Ok(())
+    }
+
+    pub fn set_project_description(&self, project_name: &str, description: &str) -> Result<()> {
+        let project_id = self.get_project_id(project_name)?;
+        self.db.execute(
+            "UPDATE projects SET description = ?2 WHERE project_id = ?1",
+            rusqlite::params![project_id, description],
+        )?;
+        Ok(())
+    }
+
+    /// Delete a project with all of its services in one transaction, see
+    /// `delete_service`.
+    pub fn delete_project(&mut self, project_name: &str) -> Result<()> {
+        let _lock = self.exclusive_lock()?;
+        let project_id = self.get_project_id(project_name)?;
+        let service_ids = {
+            let mut stmt = self
+                .db
+                .prepare("SELECT service_id FROM services WHERE project_id = ?1")?;
+            let service_ids = stmt
+                .query_map(rusqlite::params![project_id], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<i64>>>()?;
+            service_ids
+        };
+
+        let transaction = self.db.transaction()?;
+        for service_id in &service_ids {
+            delete_service_rows(&transaction, *service_id)?;
+        }
+        transaction.execute(
+            "DELETE FROM retention_policies WHERE project_id = ?1",
+            rusqlite::params![project_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM projects WHERE project_id = ?1",
+            rusqlite::params![project_id],
+        )?;
+        transaction.commit()?;
+
+        for service_id in service_ids {
+            self.remove_service_database(service_id)?;
+        }
+        Ok(())
+    }
+
     pub fn create_service(
         &self,
         project_name: &str,
@@ -205,7 +950,12 @@ impl Kodama {
         let mut stmt = self.db.prepare(
             "INSERT INTO services (project_id, service_name, description) VALUES (?1, ?2, ?3)",
         )?;
-        let service_id = stmt.insert(rusqlite::params![project_id, service_name, description])?;
+        let service_id = stmt
+            .insert(rusqlite::params![project_id, service_name, description])
+            .map_err(|err| match is_constraint_violation(&err) {
+                true => ApiError::ServiceAlreadyExists(service_name.to_string()).into(),
+                false => Error::from(err),
+            })?;
         Ok(service_id)
     }
 
@@ -238,16 +988,82 @@ impl Kodama {
         let mut rows = stmt.query(rusqlite::params![project_name, service_name])?;
         let row = rows
             .next()?
-            .ok_or(ApiError::ServiceNotFound(service_name.to_string()))?;
+            .ok_or_else(|| ApiError::ServiceNotFound(service_name.to_string()))?;
         let service_id = row.get(0)?;
         Ok(service_id)
     }
 
+    pub fn rename_service(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        new_name: &str,
+    ) -> Result<()> {
+        let _lock = self.exclusive_lock()?;
+        let service_id = self.get_service_id(project_name, service_name)?;
+        self.db
+            .execute(
+                "UPDATE services SET service_name = ?2 WHERE service_id = ?1",
+                rusqlite::params![service_id, new_name],
+            )
+            .map_err(|err| match is_constraint_violation(&err) {
+                true => ApiError::ServiceAlreadyExists(new_name.to_string()).into(),
+                false => Error::from(err),
+            })?;
+        self.services_by_ps
+            .remove(&(project_name.to_string(), service_name.to_string()));
+        Ok(())
+    }
+
+    pub fn set_service_description(
+        &self,
+        project_name: &str,
+        service_name: &str,
+        description: &str,
+    ) -> Result<()> {
+        let service_id = self.get_service_id(project_name, service_name)?;
+        self.db.execute(
+            "UPDATE services SET description = ?2 WHERE service_id = ?1",
+            rusqlite::params![service_id, description],
+        )?;
+        Ok(())
+    }
+
+    /// Delete a service with its records, metrics, retention policies and
+    /// alerts, and remove its database file.
+    pub fn delete_service(&mut self, project_name: &str, service_name: &str) -> Result<()> {
+        let _lock = self.exclusive_lock()?;
+        let service_id = self.get_service_id(project_name, service_name)?;
+        let transaction = self.db.transaction()?;
+        delete_service_rows(&transaction, service_id)?;
+        transaction.commit()?;
+        self.remove_service_database(service_id)
+    }
+
+    fn remove_service_database(&mut self, service_id: i64) -> Result<()> {
+        // close the connection before removing the database file
+        self.services_by_ps
+            .retain(|_, service| service.borrow().id != service_id);
+        self.services_by_id.remove(&service_id);
+
+        let path = PathBuf::from(&self.database_path).join(format!("service-{}.db", service_id));
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            match std::fs::remove_file(file) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
     fn get_service(&mut self, project_name: &str, service_name: &str) -> Result<ServiceRef> {
         let key = (project_name.to_string(), service_name.to_string());
         if !self.services_by_ps.contains_key(&key) {
             let service_id = self.get_service_id(project_name, service_name)?;
-            let service = Service::open(&self.database_path, service_id)?;
+            let service = Service::open(&self.database_path, service_id, self.write_batching)?;
+            self.define_tables(&service)?;
             let service = Rc::new(RefCell::new(service));
             self.services_by_ps.insert(key.clone(), service.clone());
             self.services_by_id.insert(service_id, service.clone());
@@ -258,13 +1074,127 @@ impl Kodama {
         }
     }
 
+    /// Create the tables of every registered record and metric of a service
+    /// that are missing, e.g. because the process stopped between
+    /// registering a record and committing its table.
+    fn define_tables(&self, service: &Service) -> Result<()> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT record_id FROM records WHERE service_id = ?1")?;
+        let record_ids = stmt
+            .query_map(rusqlite::params![service.id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+        for record_id in record_ids {
+            service.define_record(record_id)?;
+        }
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT metric_id FROM metrics WHERE service_id = ?1")?;
+        let metric_ids = stmt
+            .query_map(rusqlite::params![service.id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+        for metric_id in metric_ids {
+            service.define_metric(metric_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Like `get_service`, but provisions the project and service first if
+    /// auto provisioning is enabled and allows it.
+    fn get_or_provision_service(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+    ) -> Result<ServiceRef> {
+        match self.get_service(project_name, service_name) {
+            Err(Error::ApiError(ApiError::ServiceNotFound(_))) if self.auto_provision.is_some() => {
+                let allowed = self
+                    .auto_provision
+                    .as_ref()
+                    .is_some_and(|provision| provision.allows(project_name, service_name));
+                if !allowed {
+                    tracing::warn!(
+                        "not provisioning {}/{}: no matching pattern",
+                        project_name,
+                        service_name
+                    );
+                    return Err(ApiError::ServiceNotFound(service_name.to_string()).into());
+                }
+
+                self.provision_service(project_name, service_name)?;
+                self.get_service(project_name, service_name)
+            }
+            result => result,
+        }
+    }
+
+    /// Register the project and service unless they exist. Another instance
+    /// may provision the same service concurrently, both continue with the
+    /// one that was registered first.
+    fn provision_service(&self, project_name: &str, service_name: &str) -> Result<()> {
+        tracing::info!("provisioning service {}/{}", project_name, service_name);
+        self.db.execute(
+            "INSERT INTO projects (project_name, description) VALUES (?1, '') ON CONFLICT DO NOTHING",
+            rusqlite::params![project_name],
+        )?;
+        let project_id = self.get_project_id(project_name)?;
+        self.db.execute(
+            "INSERT INTO services (project_id, service_name, description) VALUES (?1, ?2, '')
+            ON CONFLICT DO NOTHING",
+            rusqlite::params![project_id, service_name],
+        )?;
+        Ok(())
+    }
+
+    /// Run `func` inside a single transaction on the database of the given
+    /// service. The transaction is rolled back if `func` fails. With write
+    /// batching the transaction is a savepoint within the open batch.
+    pub fn service_transaction<T>(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        func: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let service = self.get_or_provision_service(project_name, service_name)?;
+        {
+            let service = service.borrow();
+            if let Some(batch) = &service.batch {
+                batch.begin(&service.db)?;
+                batch.hold(true);
+            }
+            service.db.execute_batch("SAVEPOINT service_transaction;")?;
+        }
+
+        let result = func(self);
+
+        let service = service.borrow();
+        let end = match &result {
+            Ok(_) => service.db.execute_batch("RELEASE service_transaction;"),
+            Err(_) => service
+                .db
+                .execute_batch("ROLLBACK TO service_transaction; RELEASE service_transaction;"),
+        };
+        if let Some(batch) = &service.batch {
+            batch.hold(false);
+            batch.commit_if_full(&service.db)?;
+        }
+        end?;
+        if result.is_err() {
+            // tables defined by `func` were rolled back with it
+            self.define_tables(&service)?;
+        }
+        result
+    }
+
     fn create_or_get_record_table(
         &mut self,
         project_name: &str,
         service_name: &str,
         record_name: &str,
     ) -> Result<(ServiceRef, i64)> {
-        let service = self.get_service(project_name, service_name)?;
+        let service = self.get_or_provision_service(project_name, service_name)?;
         let service_id = service.borrow().id;
 
         let mut stmt = self
@@ -276,15 +1206,23 @@ impl Kodama {
             let record_id = row.get(0)?;
             Ok((service, record_id))
         } else {
-            let mut stmt = self
-                .db
-                .prepare("INSERT INTO records (service_id, record_name) VALUES (?1, ?2)")?;
-            let record_id = stmt.insert(rusqlite::params![service_id, record_name])?;
-            service.borrow_mut().define_record(record_id)?;
+            // another connection may register the same record concurrently,
+            // both continue with the record that was registered first
+            self.db.execute(
+                "INSERT INTO records (service_id, record_name) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                rusqlite::params![service_id, record_name],
+            )?;
+            let record_id = self.db.query_row(
+                "SELECT record_id FROM records WHERE service_id = ?1 AND record_name = ?2",
+                rusqlite::params![service_id, record_name],
+                |row| row.get(0),
+            )?;
+            service.borrow().define_record(record_id)?;
             Ok((service, record_id))
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn add_record(
         &mut self,
         project_name: &str,
@@ -293,16 +1231,117 @@ impl Kodama {
         group_by: &str,
         timestamp: Option<Timestamp>,
         execution_time: u64,
+        error: bool,
+        labels: &Labels,
     ) -> Result<()> {
         let (service, record_id) =
             self.create_or_get_record_table(project_name, service_name, record_name)?;
 
+        service.borrow().add_record(
+            record_id,
+            timestamp,
+            group_by,
+            execution_time,
+            error,
+            labels,
+        )?;
+        Ok(())
+    }
+
+    fn create_or_get_metric_table(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        metric_name: &str,
+    ) -> Result<(ServiceRef, i64)> {
+        let service = self.get_or_provision_service(project_name, service_name)?;
+        let service_id = service.borrow().id;
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT metric_id FROM metrics WHERE service_id = ?1 AND metric_name = ?2")?;
+        let mut rows = stmt.query(rusqlite::params![service_id, metric_name])?;
+        let row = rows.next()?;
+        if let Some(row) = row {
+            let metric_id = row.get(0)?;
+            Ok((service, metric_id))
+        } else {
+            // another connection may register the same metric concurrently,
+            // both continue with the metric that was registered first
+            self.db.execute(
+                "INSERT INTO metrics (service_id, metric_name) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                rusqlite::params![service_id, metric_name],
+            )?;
+            let metric_id = self.db.query_row(
+                "SELECT metric_id FROM metrics WHERE service_id = ?1 AND metric_name = ?2",
+                rusqlite::params![service_id, metric_name],
+                |row| row.get(0),
+            )?;
+            service.borrow().define_metric(metric_id)?;
+            Ok((service, metric_id))
+        }
+    }
+
+    pub fn add_metric(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        metric_name: &str,
+        timestamp: Option<Timestamp>,
+        value: f64,
+        labels: &Labels,
+    ) -> Result<()> {
+        let (service, metric_id) =
+            self.create_or_get_metric_table(project_name, service_name, metric_name)?;
+
         service
             .borrow()
-            .add_record(record_id, timestamp, group_by, execution_time)?;
+            .add_metric(metric_id, timestamp, value, labels)?;
         Ok(())
     }
 
+    pub fn metric_list(&self, project_name: &str, service_name: &str) -> Result<Vec<ListMetric>> {
+        let service_id = self.get_service_id(project_name, service_name)?;
+        let mut stmt = self
+            .db
+            .prepare("SELECT metric_id, metric_name FROM metrics WHERE service_id = ?1")?;
+        let metrics = stmt
+            .query_map(rusqlite::params![service_id], |row| {
+                Ok(ListMetric {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })?
+            .filter_map(|x| x.ok())
+            .collect::<Vec<_>>();
+        Ok(metrics)
+    }
+
+    fn get_metric_id(&self, service_id: i64, metric_name: &str) -> Result<i64> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT metric_id FROM metrics WHERE service_id = ?1 AND metric_name = ?2")?;
+        let mut rows = stmt.query(rusqlite::params![service_id, metric_name])?;
+        let row = rows
+            .next()?
+            .ok_or_else(|| ApiError::MetricNotFound(metric_name.to_string()))?;
+        let metric_id = row.get(0)?;
+        Ok(metric_id)
+    }
+
+    pub fn metric_values(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        metric_name: &str,
+        filter: &Labels,
+    ) -> Result<Vec<MetricValue>> {
+        let service = self.get_service(project_name, service_name)?;
+        let metric_id = self.get_metric_id(service.borrow().id, metric_name)?;
+        let values = service.borrow().metric_values(metric_id, filter)?;
+        Ok(values)
+    }
+
     pub fn record_list(&self, project_name: &str, service_name: &str) -> Result<Vec<ListRecord>> {
         let service_id = self.get_service_id(project_name, service_name)?;
         let mut stmt = self
@@ -325,20 +1364,906 @@ impl Kodama {
             .db
             .prepare("SELECT record_id FROM records WHERE service_id = ?1 AND record_name = ?2")?;
         let mut rows = stmt.query(rusqlite::params![service_id, record_name])?;
-        let row = rows.next()?.ok_or(ApiError::RecordNotFound)?;
+        let row = rows
+            .next()?
+            .ok_or_else(|| ApiError::RecordNotFound(record_name.to_string()))?;
         let record_id = row.get(0)?;
         Ok(record_id)
     }
 
+    /// Delete a record with its data, rollups and retention policies. Alerts
+    /// on the record are kept and see an empty window. The record is
+    /// unregistered before its tables are dropped, so a failure in between
+    /// only leaves unused tables behind.
+    pub fn delete_record(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        record_name: &str,
+    ) -> Result<()> {
+        let _lock = self.exclusive_lock()?;
+        let service = self.get_service(project_name, service_name)?;
+        let record_id = self.get_record_id(service.borrow().id, record_name)?;
+
+        let transaction = self.db.transaction()?;
+        transaction.execute(
+            "DELETE FROM retention_policies WHERE record_id = ?1",
+            rusqlite::params![record_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM records WHERE record_id = ?1",
+            rusqlite::params![record_id],
+        )?;
+        transaction.commit()?;
+        service.borrow().drop_record(record_id)?;
+        Ok(())
+    }
+
+    /// Delete the data of a record before `before`, or all of it, keeping
+    /// the record itself. Returns the number of deleted records.
+    pub fn truncate_record(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        record_name: &str,
+        before: Option<&Timestamp>,
+    ) -> Result<u64> {
+        let _lock = self.exclusive_lock()?;
+        let service = self.get_service(project_name, service_name)?;
+        let record_id = self.get_record_id(service.borrow().id, record_name)?;
+        let deleted = service.borrow().truncate_record(record_id, before)?;
+        Ok(deleted)
+    }
+
+    /// Delete the data of one group_by of a record. Returns the number of
+    /// deleted records.
+    pub fn delete_group(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        record_name: &str,
+        group_by: &str,
+    ) -> Result<u64> {
+        let _lock = self.exclusive_lock()?;
+        let service = self.get_service(project_name, service_name)?;
+        let record_id = self.get_record_id(service.borrow().id, record_name)?;
+        let deleted = service.borrow().delete_group(record_id, group_by)?;
+        Ok(deleted)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_series(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        record_name: &str,
+        bucket_us: u64,
+        group_by: Option<&str>,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+        filter: &Labels,
+    ) -> Result<Vec<SeriesEntry>> {
+        let service = self.get_service(project_name, service_name)?;
+        let record_id = self.get_record_id(service.borrow().id, record_name)?;
+        let entries = service
+            .borrow()
+            .record_series(record_id, bucket_us, group_by, from, to, filter)?;
+        Ok(entries)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn record_entries(
         &mut self,
         project_name: &str,
         service_name: &str,
         record_name: &str,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+        percentiles: &[f64],
+        filter: &Labels,
+        group_by_label: Option<&str>,
     ) -> Result<Vec<DataEntry>> {
         let service = self.get_service(project_name, service_name)?;
         let record_id = self.get_record_id(service.borrow().id, record_name)?;
-        let entries = service.borrow().record_entries(record_id)?;
+        let entries = service.borrow().record_entries(
+            record_id,
+            from,
+            to,
+            percentiles,
+            filter,
+            group_by_label,
+        )?;
         Ok(entries)
     }
+
+    /// Set the retention of a project, or of a service or record within it.
+    /// `None` removes the policy, so the enclosing one applies again.
+    pub fn set_retention(
+        &self,
+        project_name: &str,
+        service_name: Option<&str>,
+        record_name: Option<&str>,
+        keep_us: Option<u64>,
+    ) -> Result<()> {
+        if keep_us == Some(0) {
+            return Err(ApiError::InvalidRetention("keep must be positive".to_string()).into());
+        }
+
+        let project_id = self.get_project_id(project_name)?;
+        let service_id = match service_name {
+            Some(service_name) => Some(self.get_service_id(project_name, service_name)?),
+            None => None,
+        };
+        let record_id = match (service_id, record_name) {
+            (Some(service_id), Some(record_name)) => {
+                Some(self.get_record_id(service_id, record_name)?)
+            }
+            (None, Some(_)) => {
+                return Err(
+                    ApiError::InvalidRetention("a record requires a service".to_string()).into(),
+                )
+            }
+            (_, None) => None,
+        };
+
+        self.db.execute(
+            "DELETE FROM retention_policies
+            WHERE project_id = ?1 AND service_id IS ?2 AND record_id IS ?3",
+            rusqlite::params![project_id, service_id, record_id],
+        )?;
+        if let Some(keep_us) = keep_us {
+            self.db.execute(
+                "INSERT INTO retention_policies (project_id, service_id, record_id, keep_us)
+                VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![project_id, service_id, record_id, keep_us],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn retention_list(&self, project_name: Option<&str>) -> Result<Vec<RetentionPolicy>> {
+        let mut stmt = self.db.prepare(
+            "SELECT p.project_name, s.service_name, r.record_name, rp.keep_us
+            FROM retention_policies AS rp
+            JOIN projects AS p ON rp.project_id = p.project_id
+            LEFT JOIN services AS s ON rp.service_id = s.service_id
+            LEFT JOIN records AS r ON rp.record_id = r.record_id
+            WHERE ?1 IS NULL OR p.project_name = ?1
+            ORDER BY p.project_name, s.service_name, r.record_name",
+        )?;
+        let policies = stmt
+            .query_map(rusqlite::params![project_name], |row| {
+                Ok(RetentionPolicy {
+                    project_name: row.get(0)?,
+                    service_name: row.get(1)?,
+                    record_name: row.get(2)?,
+                    keep_us: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(policies)
+    }
+
+    /// Delete record and metric samples older than their retention allows,
+    /// returning the number of deleted rows.
+    pub fn prune(&self, now: &Timestamp) -> Result<u64> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT project_id, service_id, record_id, keep_us FROM retention_policies")?;
+        let policies = Policies(
+            stmt.query_map(rusqlite::params![], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?,
+        );
+        if policies.0.is_empty() {
+            return Ok(0);
+        }
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT service_id, project_id FROM services")?;
+        let services = stmt
+            .query_map(rusqlite::params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, i64)>>>()?;
+
+        // a broken service database must not stop the others from being pruned
+        let mut deleted = 0;
+        for (service_id, project_id) in services {
+            match self.prune_service(&policies, service_id, project_id, now) {
+                Ok(count) => deleted += count,
+                Err(err) => tracing::error!("unable to prune service {}: {:?}", service_id, err),
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    fn prune_service(
+        &self,
+        policies: &Policies,
+        service_id: i64,
+        project_id: i64,
+        now: &Timestamp,
+    ) -> Result<u64> {
+        let mut tables = Vec::new();
+
+        let mut stmt = self
+            .db
+            .prepare("SELECT record_id FROM records WHERE service_id = ?1")?;
+        for record_id in stmt.query_map(rusqlite::params![service_id], |row| row.get(0))? {
+            let record_id: i64 = record_id?;
+            if let Some(keep_us) = policies.keep_us(project_id, service_id, Some(record_id)) {
+                tables.push((format!("record_{}", record_id), keep_us, Some(record_id)));
+            }
+        }
+
+        // metrics, logs and events follow the service policy
+        let service_keep_us = policies.keep_us(project_id, service_id, None);
+        if let Some(keep_us) = service_keep_us {
+            let mut stmt = self
+                .db
+                .prepare("SELECT metric_id FROM metrics WHERE service_id = ?1")?;
+            for metric_id in stmt.query_map(rusqlite::params![service_id], |row| row.get(0))? {
+                let metric_id: i64 = metric_id?;
+                tables.push((format!("metric_{}", metric_id), keep_us, None));
+            }
+        }
+
+        if tables.is_empty() && service_keep_us.is_none() {
+            return Ok(0);
+        }
+
+        let Some(service) = Service::open_existing(&self.database_path, service_id)? else {
+            return Ok(0);
+        };
+        if let Some(keep_us) = service_keep_us {
+            for table in ["logs", "events"] {
+                if service.has_table(table)? {
+                    tables.push((table.to_string(), keep_us, None));
+                }
+            }
+        }
+
+        let mut deleted = 0;
+        for (table, keep_us, record_id) in tables {
+            let prune = || {
+                let mut before = now.microseconds.saturating_sub(keep_us);
+                if let Some(record_id) = record_id {
+                    // keep records that haven't been rolled up yet
+                    let watermarks = service.rollup_watermarks(record_id)?;
+                    if let Some((_, watermark)) = watermarks
+                        .iter()
+                        .find(|(resolution, _)| *resolution == rollup::MINUTE)
+                    {
+                        before = before.min(*watermark);
+                    }
+                }
+                service.prune_table(&table, before as i64, record_id)
+            };
+
+            match prune() {
+                Ok(count) => {
+                    if count > 0 {
+                        tracing::info!(
+                            "pruned {} rows from service {} {}",
+                            count,
+                            service_id,
+                            table
+                        );
+                    }
+                    deleted += count;
+                }
+                Err(err) => tracing::error!(
+                    "unable to prune service {} {}: {:?}",
+                    service_id,
+                    table,
+                    err
+                ),
+            }
+        }
+
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TestDatabase;
+
+    fn count(kodama: &mut Kodama, service_name: &str, record_name: &str) -> i64 {
+        kodama
+            .record_entries(
+                "shop",
+                service_name,
+                record_name,
+                None,
+                None,
+                &[],
+                &Labels::new(),
+                None,
+            )
+            .unwrap()
+            .iter()
+            .map(|entry| entry.count)
+            .sum()
+    }
+
+    #[test]
+    fn define_missing_tables() {
+        let database = TestDatabase::new("define-tables");
+        let mut kodama = database
+            .instance()
+            .with_write_batching(WriteBatching::default());
+        let add = |kodama: &mut Kodama, record_name: &str| {
+            kodama.add_record(
+                "shop",
+                "api",
+                record_name,
+                "/",
+                None,
+                10,
+                false,
+                &Labels::new(),
+            )
+        };
+
+        // the table of a record registered in a failed transaction is rolled
+        // back, but the registration is not
+        let result = kodama.service_transaction("shop", "api", |kodama| {
+            add(kodama, "checkout")?;
+            Err::<(), _>(ApiError::InvalidTimestamp.into())
+        });
+        assert!(result.is_err());
+        add(&mut kodama, "checkout").unwrap();
+        kodama.flush().unwrap();
+        assert_eq!(count(&mut kodama, "api", "checkout"), 1);
+
+        // a table lost after registering is recreated when the service opens
+        add(&mut kodama, "login").unwrap();
+        kodama.flush().unwrap();
+        let service_id = kodama.get_service_id("shop", "api").unwrap();
+        let record_id = kodama.get_record_id(service_id, "login").unwrap();
+        drop(kodama);
+        let db =
+            rusqlite::Connection::open(database.path.join(format!("service-{}.db", service_id)))
+                .unwrap();
+        db.execute_batch(&format!("DROP TABLE record_{};", record_id))
+            .unwrap();
+        drop(db);
+
+        let mut kodama = database.instance();
+        add(&mut kodama, "login").unwrap();
+        assert_eq!(count(&mut kodama, "api", "login"), 1);
+    }
+
+    #[test]
+    fn record_percentiles() {
+        let database = TestDatabase::new("percentiles");
+        let mut kodama = database.instance();
+        // both groups inserted interleaved and out of order
+        kodama
+            .service_transaction("shop", "api", |kodama| {
+                for i in 0..1000 {
+                    let mut add = |group_by: &str, value: u64| {
+                        kodama.add_record(
+                            "shop",
+                            "api",
+                            "checkout",
+                            group_by,
+                            None,
+                            value,
+                            false,
+                            &Labels::new(),
+                        )
+                    };
+                    add("/a", i * 7919 % 1000 + 1)?;
+                    if i < 100 {
+                        add("/", i * 37 % 100 + 1)?;
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        let entries = kodama
+            .record_entries(
+                "shop",
+                "api",
+                "checkout",
+                None,
+                None,
+                &[99.9],
+                &Labels::new(),
+                None,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.group_by,
+                    entry.count,
+                    entry.min,
+                    entry.max,
+                    entry.avg,
+                    entry.p50,
+                    entry.p95,
+                    entry.percentiles[0].value,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                ("/".to_string(), 100, 1, 100, 51, 50, 95, 100),
+                ("/a".to_string(), 1000, 1, 1000, 501, 500, 950, 999),
+            ]
+        );
+    }
+
+    #[test]
+    fn record_series_buckets() {
+        let database = TestDatabase::new("series");
+        let mut kodama = database.instance();
+        let at = |seconds: u64, us: u64| Timestamp {
+            microseconds: seconds * 1_000_000 + us,
+        };
+        let mut records = Vec::new();
+        for i in 0..100 {
+            let value = i * 37 % 100 + 1;
+            records.push(("/", at(60 + i / 2, 0), value, value > 90));
+        }
+        records.push(("/a", at(61, 0), 5000, false));
+        records.push(("/a", at(119, 999_999), 5000, false));
+        // bucket edges, nothing in the minute in between
+        records.push(("/", at(180, 0), 10, false));
+        records.push(("/", at(200, 0), 30, true));
+        records.push(("/", at(210, 0), 20, false));
+        records.push(("/", at(239, 999_999), 40, false));
+        kodama
+            .service_transaction("shop", "api", |kodama| {
+                for (group_by, timestamp, value, error) in &records {
+                    kodama.add_record(
+                        "shop",
+                        "api",
+                        "checkout",
+                        group_by,
+                        Some(timestamp.clone()),
+                        *value,
+                        *error,
+                        &Labels::new(),
+                    )?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        let mut series = |group_by: Option<&str>, from: Option<&Timestamp>| {
+            kodama
+                .record_series(
+                    "shop",
+                    "api",
+                    "checkout",
+                    60_000_000,
+                    group_by,
+                    from,
+                    None,
+                    &Labels::new(),
+                )
+                .unwrap()
+                .into_iter()
+                .map(|entry| {
+                    (
+                        entry.timestamp.microseconds,
+                        entry.count,
+                        entry.errors,
+                        entry.avg,
+                        entry.p50,
+                        entry.p95,
+                        entry.p99,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            series(Some("/"), None),
+            vec![
+                (60_000_000, 100, 10, 51, 50, 95, 99),
+                (180_000_000, 4, 1, 25, 20, 40, 40),
+            ]
+        );
+        assert_eq!(
+            series(None, None),
+            vec![
+                (60_000_000, 102, 10, 148, 51, 97, 5000),
+                (180_000_000, 4, 1, 25, 20, 40, 40),
+            ]
+        );
+        // buckets stay aligned to the epoch when the range is not
+        assert_eq!(
+            series(None, Some(&at(180, 1))),
+            vec![(180_000_000, 3, 1, 30, 30, 40, 40)]
+        );
+    }
+
+    #[test]
+    fn provision_concurrently() {
+        let database = TestDatabase::new("provision");
+        let mut first = database
+            .instance()
+            .with_auto_provision(AutoProvision::all());
+        let mut second = database
+            .instance()
+            .with_auto_provision(AutoProvision::all());
+
+        // the second instance checked for the service before the first one
+        // registered it
+        first.provision_service("billing", "web").unwrap();
+        second.provision_service("billing", "web").unwrap();
+
+        let add = |kodama: &mut Kodama| {
+            kodama.add_record(
+                "billing",
+                "web",
+                "request",
+                "/",
+                None,
+                100,
+                false,
+                &Labels::new(),
+            )
+        };
+        add(&mut first).unwrap();
+        add(&mut second).unwrap();
+        assert_eq!(first.project_list().unwrap().len(), 2);
+        assert_eq!(first.service_list("billing").unwrap().len(), 1);
+
+        let entries = second
+            .record_entries(
+                "billing",
+                "web",
+                "request",
+                None,
+                None,
+                &[],
+                &Labels::new(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.count).sum::<i64>(), 2);
+    }
+
+    #[test]
+    fn prune_each_service() {
+        let database = TestDatabase::new("prune");
+        let mut kodama = database.instance();
+        kodama.create_service("shop", "web", "").unwrap();
+        let idle_id = kodama.create_service("shop", "idle", "").unwrap();
+        let at = |seconds: u64| Timestamp {
+            microseconds: seconds * 1_000_000,
+        };
+        for service_name in ["api", "web"] {
+            for seconds in [10, 20, 100] {
+                kodama
+                    .add_record(
+                        "shop",
+                        service_name,
+                        "checkout",
+                        "/",
+                        Some(at(seconds)),
+                        10,
+                        false,
+                        &Labels::new(),
+                    )
+                    .unwrap();
+            }
+        }
+        kodama
+            .set_retention("shop", None, None, Some(60 * 1_000_000))
+            .unwrap();
+
+        // a broken service database does not stop the others from being pruned
+        let api_id = kodama.get_service_id("shop", "api").unwrap();
+        let record_id = kodama.get_record_id(api_id, "checkout").unwrap();
+        let db = rusqlite::Connection::open(database.path.join(format!("service-{}.db", api_id)))
+            .unwrap();
+        db.execute_batch(&format!("DROP TABLE record_{};", record_id))
+            .unwrap();
+
+        assert_eq!(kodama.prune(&at(120)).unwrap(), 2);
+        assert_eq!(count(&mut kodama, "web", "checkout"), 1);
+        // services that never stored anything don't get a database
+        assert!(!database
+            .path
+            .join(format!("service-{}.db", idle_id))
+            .exists());
+    }
+
+    #[test]
+    fn delete_record_data() {
+        let database = TestDatabase::new("delete-record");
+        let mut kodama = database.instance();
+        let at = |minutes: u64| Timestamp {
+            microseconds: minutes * rollup::MINUTE + 10 * 1_000_000,
+        };
+        for (minutes, group_by) in [(1, "/"), (2, "/a"), (61, "/"), (125, "/a")] {
+            kodama
+                .add_record(
+                    "shop",
+                    "api",
+                    "checkout",
+                    group_by,
+                    Some(at(minutes)),
+                    10,
+                    false,
+                    &Labels::new(),
+                )
+                .unwrap();
+        }
+        let service_id = kodama.get_service_id("shop", "api").unwrap();
+        let record_id = kodama.get_record_id(service_id, "checkout").unwrap();
+        kodama.rollup(&at(180)).unwrap();
+
+        let db =
+            rusqlite::Connection::open(database.path.join(format!("service-{}.db", service_id)))
+                .unwrap();
+        let query = |sql: &str| {
+            db.query_row(&sql.replace("{}", &record_id.to_string()), [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap()
+        };
+        // raw records, minute and hour rollups and rollup states
+        let counts = || {
+            (
+                query("SELECT COUNT(*) FROM record_{}"),
+                query(&format!(
+                    "SELECT COUNT(*) FROM rollup_{{}} WHERE resolution = {}",
+                    rollup::MINUTE
+                )),
+                query(&format!(
+                    "SELECT COUNT(*) FROM rollup_{{}} WHERE resolution = {}",
+                    rollup::HOUR
+                )),
+                query("SELECT COUNT(*) FROM rollup_state WHERE record_id = {}"),
+            )
+        };
+        assert_eq!(counts(), (4, 4, 3, 2));
+
+        // refused while a server holds the database
+        let lock = kodama.server_lock().unwrap();
+        assert!(matches!(
+            kodama.truncate_record("shop", "api", "checkout", None),
+            Err(Error::ApiError(ApiError::DatabaseInUse))
+        ));
+        assert!(matches!(
+            kodama.delete_group("shop", "api", "checkout", "/a"),
+            Err(Error::ApiError(ApiError::DatabaseInUse))
+        ));
+        assert!(matches!(
+            kodama.delete_record("shop", "api", "checkout"),
+            Err(Error::ApiError(ApiError::DatabaseInUse))
+        ));
+        drop(lock);
+        assert_eq!(counts(), (4, 4, 3, 2));
+
+        // the minute and hour spanning the cutoff are kept
+        let before = Timestamp {
+            microseconds: 61 * rollup::MINUTE + 30 * 1_000_000,
+        };
+        assert_eq!(
+            kodama
+                .truncate_record("shop", "api", "checkout", Some(&before))
+                .unwrap(),
+            3
+        );
+        assert_eq!(counts(), (1, 2, 1, 2));
+
+        assert_eq!(
+            kodama
+                .delete_group("shop", "api", "checkout", "/a")
+                .unwrap(),
+            1
+        );
+        assert_eq!(counts(), (0, 1, 1, 2));
+
+        kodama.delete_record("shop", "api", "checkout").unwrap();
+        assert!(kodama.get_record_id(service_id, "checkout").is_err());
+        assert_eq!(
+            query("SELECT COUNT(*) FROM sqlite_master WHERE name IN ('record_{}', 'rollup_{}')"),
+            0
+        );
+        assert_eq!(
+            query("SELECT COUNT(*) FROM rollup_state WHERE record_id = {}"),
+            0
+        );
+    }
+
+    #[test]
+    fn rename_and_describe() {
+        let database = TestDatabase::new("rename");
+        let mut kodama = database.instance();
+        kodama
+            .add_record(
+                "shop",
+                "api",
+                "checkout",
+                "/",
+                None,
+                10,
+                false,
+                &Labels::new(),
+            )
+            .unwrap();
+
+        kodama.rename_project("shop", "store").unwrap();
+        kodama.rename_service("store", "api", "backend").unwrap();
+        assert!(matches!(
+            kodama.get_service_id("shop", "api"),
+            Err(Error::ApiError(ApiError::ServiceNotFound(_)))
+        ));
+        let service_id = kodama.get_service_id("store", "backend").unwrap();
+        assert!(kodama.get_record_id(service_id, "checkout").is_ok());
+        // the cached service of the old name is not used anymore
+        assert!(kodama
+            .add_record(
+                "shop",
+                "api",
+                "checkout",
+                "/",
+                None,
+                10,
+                false,
+                &Labels::new()
+            )
+            .is_err());
+
+        kodama.create_project("shop", "").unwrap();
+        assert!(matches!(
+            kodama.rename_project("store", "shop"),
+            Err(Error::ApiError(ApiError::ProjectAlreadyExists(_)))
+        ));
+
+        kodama
+            .set_project_description("store", "online store")
+            .unwrap();
+        kodama
+            .set_service_description("store", "backend", "public api")
+            .unwrap();
+        let project = kodama
+            .project_list()
+            .unwrap()
+            .into_iter()
+            .find(|project| project.name == "store")
+            .unwrap();
+        assert_eq!(project.description, "online store");
+        let services = kodama.service_list("store").unwrap();
+        assert_eq!(services[0].name, "backend");
+        assert_eq!(services[0].description, "public api");
+    }
+
+    #[test]
+    fn delete_cascade() {
+        let database = TestDatabase::new("delete");
+        let mut kodama = database.instance();
+        kodama.create_service("shop", "web", "").unwrap();
+        kodama.create_project("blog", "").unwrap();
+        kodama.create_service("blog", "api", "").unwrap();
+        kodama
+            .create_notifier(&notifier::CreateRequest {
+                notifier_name: "ops".to_string(),
+                kind: notifier::NotifierKind::File,
+                target: "/dev/null".to_string(),
+            })
+            .unwrap();
+        for (project_name, service_name) in [("shop", "api"), ("shop", "web"), ("blog", "api")] {
+            kodama
+                .add_record(
+                    project_name,
+                    service_name,
+                    "checkout",
+                    "/",
+                    Some(Timestamp { microseconds: 10 }),
+                    500,
+                    false,
+                    &Labels::new(),
+                )
+                .unwrap();
+            kodama
+                .add_metric(
+                    project_name,
+                    service_name,
+                    "memory",
+                    None,
+                    1.0,
+                    &Labels::new(),
+                )
+                .unwrap();
+            kodama
+                .create_alert(&alert::CreateRequest {
+                    project_name: project_name.to_string(),
+                    service_name: service_name.to_string(),
+                    alert_name: "slow".to_string(),
+                    target: alert::AlertTarget::Record,
+                    target_name: "checkout".to_string(),
+                    aggregation: alert::Aggregation::Max,
+                    window_us: 60,
+                    comparison: alert::Comparison::Gt,
+                    threshold: 100.0,
+                })
+                .unwrap();
+            kodama
+                .route_alert(&notifier::RouteRequest {
+                    project_name: project_name.to_string(),
+                    service_name: service_name.to_string(),
+                    alert_name: "slow".to_string(),
+                    notifier_name: "ops".to_string(),
+                    repeat_us: None,
+                })
+                .unwrap();
+            kodama
+                .set_retention(project_name, Some(service_name), None, Some(60))
+                .unwrap();
+        }
+        kodama.set_retention("shop", None, None, Some(60)).unwrap();
+        kodama
+            .evaluate_alerts(&Timestamp { microseconds: 20 })
+            .unwrap();
+        kodama.flush().unwrap();
+
+        let rows = |kodama: &Kodama| {
+            [
+                "alert_routes",
+                "alert_history",
+                "alert_rules",
+                "retention_policies",
+                "records",
+                "metrics",
+                "services",
+                "projects",
+                "notifiers",
+            ]
+            .map(|table| {
+                kodama
+                    .db
+                    .query_row(
+                        &format!("SELECT COUNT(*) FROM {}", table),
+                        rusqlite::params![],
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .unwrap()
+            })
+        };
+        assert_eq!(rows(&kodama), [3, 3, 3, 4, 3, 3, 3, 2, 1]);
+        let service_file =
+            |service_id: i64| database.path.join(format!("service-{}.db", service_id));
+        let shop_ids = ["api", "web"].map(|name| kodama.get_service_id("shop", name).unwrap());
+        let blog_id = kodama.get_service_id("blog", "api").unwrap();
+
+        // refused while a server holds the database
+        let lock = kodama.server_lock().unwrap();
+        assert!(matches!(
+            kodama.delete_project("shop"),
+            Err(Error::ApiError(ApiError::DatabaseInUse))
+        ));
+        assert!(matches!(
+            kodama.rename_service("blog", "api", "web"),
+            Err(Error::ApiError(ApiError::DatabaseInUse))
+        ));
+        drop(lock);
+
+        kodama.delete_service("shop", "web").unwrap();
+        assert_eq!(rows(&kodama), [2, 2, 2, 3, 2, 2, 2, 2, 1]);
+        assert!(!service_file(shop_ids[1]).exists());
+
+        kodama.delete_project("shop").unwrap();
+        assert_eq!(rows(&kodama), [1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert!(!service_file(shop_ids[0]).exists());
+        assert!(service_file(blog_id).exists());
+        assert!(matches!(
+            kodama.get_project_id("shop"),
+            Err(Error::ApiError(ApiError::ProjectNotFound(_)))
+        ));
+    }
 }
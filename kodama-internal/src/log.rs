@@ -0,0 +1,232 @@
+use crate::{labels, ApiError, Kodama, Result, Service};
+use kodama_api::{Labels, LogLevel, Timestamp};
+
+/// Number of log entries returned by a search without a limit.
+pub const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SearchRequest {
+    pub project_name: String,
+    pub service_name: String,
+    /// Only include entries at or above this level
+    #[serde(default)]
+    pub level: Option<LogLevel>,
+    /// Only include entries at or after this time
+    #[serde(default)]
+    pub from: Option<Timestamp>,
+    /// Only include entries before this time
+    #[serde(default)]
+    pub to: Option<Timestamp>,
+    /// Only include entries whose message contains all of these words
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Maximum number of entries, newest first
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SearchResponse {
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct LogEntry {
+    pub timestamp: Timestamp,
+    pub level: LogLevel,
+    pub target: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub fields: Labels,
+}
+
+/// FTS5 query matching messages containing every word of `text`. Words are
+/// quoted, so FTS5 operators in `text` are searched for literally.
+fn match_query(text: &str) -> Option<String> {
+    let words = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+impl Service {
+    pub fn add_log(
+        &self,
+        timestamp: Option<Timestamp>,
+        level: LogLevel,
+        target: &str,
+        message: &str,
+        fields: &Labels,
+    ) -> Result<()> {
+        let fields = labels::fields_to_json(fields)?;
+        let mut stmt = self.db.prepare_cached(
+            "INSERT INTO logs (timestamp, level, target, message, fields) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+
+        let timestamp = if let Some(timestamp) = timestamp {
+            timestamp
+        } else if let Some(timestamp) = Timestamp::now() {
+            timestamp
+        } else {
+            return Err(ApiError::InvalidTimestamp.into());
+        };
+
+        self.begin_write()?;
+        stmt.execute(rusqlite::params![timestamp, level, target, message, fields])?;
+        self.end_write()
+    }
+
+    /// Log entries matching all given filters, newest first.
+    pub fn log_search(
+        &self,
+        level: Option<LogLevel>,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+        text: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<LogEntry>> {
+        let mut stmt = self.db.prepare(
+            "
+SELECT timestamp, level, target, message, fields
+FROM logs
+WHERE (?1 IS NULL OR level >= ?1) AND (?2 IS NULL OR timestamp >= ?2) AND (?3 IS NULL OR timestamp < ?3)
+AND (?4 IS NULL OR id IN (SELECT rowid FROM logs_fts WHERE logs_fts MATCH ?4))
+ORDER BY timestamp DESC, id DESC
+LIMIT ?5",
+        )?;
+        let entries = stmt
+            .query_map(
+                rusqlite::params![level, from, to, text.and_then(match_query), limit],
+                |row| {
+                    Ok(LogEntry {
+                        timestamp: row.get(0)?,
+                        level: row.get(1)?,
+                        target: row.get(2)?,
+                        message: row.get(3)?,
+                        fields: labels::from_json(&row.get::<_, String>(4)?),
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+}
+
+impl Kodama {
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_log(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        timestamp: Option<Timestamp>,
+        level: LogLevel,
+        target: &str,
+        message: &str,
+        fields: &Labels,
+    ) -> Result<()> {
+        let service = self.get_or_provision_service(project_name, service_name)?;
+        service
+            .borrow()
+            .add_log(timestamp, level, target, message, fields)?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn log_search(
+        &mut self,
+        project_name: &str,
+        service_name: &str,
+        level: Option<LogLevel>,
+        from: Option<&Timestamp>,
+        to: Option<&Timestamp>,
+        text: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<LogEntry>> {
+        let service = self.get_service(project_name, service_name)?;
+        let entries = service.borrow().log_search(level, from, to, text, limit)?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(crate::migration::logs_sql()).unwrap();
+        let service = Service {
+            id: 1,
+            db,
+            batch: None,
+        };
+
+        // field keys aren't limited like label keys
+        let fields = Labels::from([
+            ("user".to_string(), "42".to_string()),
+            ("request id".to_string(), "a1".to_string()),
+        ]);
+        for (microseconds, level, message) in [
+            (1, LogLevel::Info, "user logged in"),
+            (2, LogLevel::Warn, "slow query on \"users\" table"),
+            (3, LogLevel::Error, "connection refused"),
+            (4, LogLevel::Debug, "user session refreshed"),
+        ] {
+            service
+                .add_log(
+                    Some(Timestamp { microseconds }),
+                    level,
+                    "app",
+                    message,
+                    &fields,
+                )
+                .unwrap();
+        }
+
+        let messages = |level, from, text| {
+            service
+                .log_search(level, from, None, text, DEFAULT_LIMIT)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(messages(None, None, None).len(), 4);
+        assert_eq!(
+            messages(None, None, Some("user")),
+            ["user session refreshed", "user logged in"]
+        );
+        assert_eq!(
+            messages(Some(LogLevel::Info), None, Some("user")),
+            ["user logged in"]
+        );
+        assert_eq!(
+            messages(Some(LogLevel::Warn), None, None),
+            ["connection refused", "slow query on \"users\" table"]
+        );
+        assert_eq!(
+            messages(None, Some(&Timestamp { microseconds: 3 }), None),
+            ["user session refreshed", "connection refused"]
+        );
+        assert_eq!(
+            messages(None, None, Some("\"users\" table")),
+            ["slow query on \"users\" table"]
+        );
+        assert!(messages(None, None, Some("NOT refused")).is_empty());
+        assert_eq!(messages(None, None, Some("   ")).len(), 4);
+
+        let entries = service.log_search(None, None, None, None, 1).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].level, LogLevel::Debug);
+        assert_eq!(entries[0].fields, fields);
+
+        assert_eq!(service.prune_table("logs", 3, None).unwrap(), 2);
+        assert!(messages(None, None, Some("logged")).is_empty());
+    }
+}
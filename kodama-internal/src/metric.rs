@@ -1,3 +1,5 @@
+use kodama_api::{Labels, Timestamp};
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct PushRequest {
     pub project_name: String,
@@ -11,3 +13,46 @@ pub struct PushRequest {
 pub struct PushResponse {
     pub metric_id: i64,
 }
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListRequest {
+    pub project_name: String,
+    pub service_name: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListResponse {
+    pub metrics: Vec<ListMetric>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListMetric {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct DataRequest {
+    pub project_name: String,
+    pub service_name: String,
+    pub metric_name: String,
+    /// Only include values carrying all of these labels
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct DataResponse {
+    pub values: Vec<MetricValue>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct MetricValue {
+    /// Time the value was measured
+    pub timestamp: Timestamp,
+    /// Measured value
+    pub value: f64,
+    /// Labels the value was pushed with
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+}
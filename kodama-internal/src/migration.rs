@@ -0,0 +1,294 @@
+use crate::Result;
+use kodama_api::Migrations;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct MigrationStatus {
+    /// Database file name, e.g. `kodama.db` or `service-1.db`
+    pub database: String,
+    /// Migration version
+    pub version: String,
+    /// Whether the migration has been applied
+    pub applied: bool,
+}
+
+/// Migrations for the main `kodama.db` database.
+pub(crate) fn kodama_migrations() -> Migrations {
+    Migrations::new()
+        .with_migration("0001_initial", include_str!("../../schema/schema.sql"))
+        .with_migration(
+            "0002_retention",
+            include_str!("../../schema/0002_retention.sql"),
+        )
+        .with_migration("0003_alerts", include_str!("../../schema/0003_alerts.sql"))
+        .with_migration(
+            "0004_notifiers",
+            include_str!("../../schema/0004_notifiers.sql"),
+        )
+        .with_migration(
+            "0005_unique_names",
+            include_str!("../../schema/0005_unique_names.sql"),
+        )
+}
+
+/// Migrations for a per-service `service-{id}.db` database. Record and metric
+/// tables are created on demand, so migrations touching them are generated
+/// from the tables present in `db`.
+pub(crate) fn service_migrations(db: &rusqlite::Connection) -> Result<Migrations> {
+    Ok(Migrations::new()
+        .with_migration("0001_record_timestamp", record_timestamp_sql(db)?)
+        .with_migration("0002_rollups", rollups_sql(db)?)
+        .with_migration("0003_labels", labels_sql(db)?)
+        .with_migration("0004_logs", logs_sql())
+        .with_migration("0005_events", events_sql())
+        .with_migration("0006_rollup_rows", rollup_rows_sql()))
+}
+
+pub(crate) fn record_table_sql(record_id: i64) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS record_{0} (
+            timestamp INTEGER NOT NULL,
+            group_by TEXT NOT NULL,
+            execution_time_us INTEGER NOT NULL,
+            error INTEGER DEFAULT 0,
+            labels TEXT NOT NULL DEFAULT '{{}}'
+        );
+        CREATE INDEX IF NOT EXISTS idx_record_{0}_timestamp ON record_{0} (timestamp);
+        CREATE INDEX IF NOT EXISTS idx_record_{0}_group_by ON record_{0} (group_by, execution_time_us);",
+        record_id
+    )
+}
+
+pub(crate) fn metric_table_sql(metric_id: i64) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS metric_{0} (
+            timestamp INTEGER NOT NULL,
+            value REAL NOT NULL,
+            labels TEXT NOT NULL DEFAULT '{{}}'
+        );
+        CREATE INDEX IF NOT EXISTS idx_metric_{0}_timestamp ON metric_{0} (timestamp);",
+        metric_id
+    )
+}
+
+/// Rollups of a record per resolution (in microseconds), bucket start and
+/// group_by, see `rollup::Aggregate`.
+pub(crate) fn rollup_table_sql(record_id: i64) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS rollup_{0} (
+            resolution INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            group_by TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            sum INTEGER NOT NULL,
+            min INTEGER NOT NULL,
+            max INTEGER NOT NULL,
+            errors INTEGER NOT NULL,
+            sketch BLOB NOT NULL,
+            PRIMARY KEY (resolution, timestamp, group_by)
+        );",
+        record_id
+    )
+}
+
+/// Add the rollup watermarks and a rollup table for every existing record.
+fn rollups_sql(db: &rusqlite::Connection) -> Result<String> {
+    let mut sql = "CREATE TABLE IF NOT EXISTS rollup_state (
+            record_id INTEGER NOT NULL,
+            resolution INTEGER NOT NULL,
+            watermark INTEGER NOT NULL,
+            PRIMARY KEY (record_id, resolution)
+        );
+        "
+    .to_string();
+
+    let mut stmt = db.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name GLOB 'record_[0-9]*'",
+    )?;
+    let tables = stmt
+        .query_map(rusqlite::params![], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for table in tables {
+        if let Some(record_id) = table
+            .strip_prefix("record_")
+            .and_then(|id| id.parse::<i64>().ok())
+        {
+            sql.push_str(&rollup_table_sql(record_id));
+        }
+    }
+
+    Ok(sql)
+}
+
+/// Log entries with a full-text index over their messages, kept in sync by
+/// triggers.
+pub(crate) fn logs_sql() -> &'static str {
+    "CREATE TABLE IF NOT EXISTS logs (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        level INTEGER NOT NULL,
+        target TEXT NOT NULL,
+        message TEXT NOT NULL,
+        fields TEXT NOT NULL DEFAULT '{}'
+    );
+    CREATE INDEX IF NOT EXISTS idx_logs_timestamp ON logs (timestamp);
+    CREATE VIRTUAL TABLE IF NOT EXISTS logs_fts USING fts5(message, content = 'logs', content_rowid = 'id');
+    CREATE TRIGGER IF NOT EXISTS logs_insert AFTER INSERT ON logs BEGIN
+        INSERT INTO logs_fts (rowid, message) VALUES (new.id, new.message);
+    END;
+    CREATE TRIGGER IF NOT EXISTS logs_delete AFTER DELETE ON logs BEGIN
+        INSERT INTO logs_fts (logs_fts, rowid, message) VALUES ('delete', old.id, old.message);
+    END;"
+}
+
+/// Events by name with their JSON payload.
+pub(crate) fn events_sql() -> &'static str {
+    "CREATE TABLE IF NOT EXISTS events (
+        timestamp INTEGER NOT NULL,
+        event_name TEXT NOT NULL,
+        payload TEXT NOT NULL DEFAULT 'null'
+    );
+    CREATE INDEX IF NOT EXISTS idx_events_timestamp ON events (timestamp);
+    CREATE INDEX IF NOT EXISTS idx_events_event_name ON events (event_name, timestamp);"
+}
+
+/// Highest raw record rowid included in the rollups of each record, so that
+/// records arriving after their minute was rolled up can be told apart.
+fn rollup_rows_sql() -> &'static str {
+    "CREATE TABLE IF NOT EXISTS rollup_rows (
+        record_id INTEGER PRIMARY KEY,
+        max_rowid INTEGER NOT NULL
+    );"
+}
+
+/// Add a JSON `labels` column to every record and metric table lacking one.
+fn labels_sql(db: &rusqlite::Connection) -> Result<String> {
+    let mut stmt = db.prepare(
+        "SELECT m.name FROM sqlite_master AS m
+            WHERE m.type = 'table' AND (m.name GLOB 'record_[0-9]*' OR m.name GLOB 'metric_[0-9]*')
+            AND NOT EXISTS (SELECT 1 FROM pragma_table_info(m.name) AS c WHERE c.name = 'labels')",
+    )?;
+    let tables = stmt
+        .query_map(rusqlite::params![], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut sql = String::new();
+    for table in tables {
+        sql.push_str(&format!(
+            "ALTER TABLE {} ADD COLUMN labels TEXT NOT NULL DEFAULT '{{}}';\n",
+            table
+        ));
+    }
+
+    Ok(sql)
+}
+
+/// Record tables used to be keyed by `timestamp INTEGER PRIMARY KEY`, which
+/// rejects two records arriving in the same microsecond. Rebuild any such
+/// table without the key, keeping its data. The layout is the one of this
+/// migration, later columns are added by later migrations.
+fn record_timestamp_sql(db: &rusqlite::Connection) -> Result<String> {
+    let mut stmt = db.prepare(
+        "SELECT m.name FROM sqlite_master AS m, pragma_table_info(m.name) AS c
+            WHERE m.type = 'table' AND m.name GLOB 'record_[0-9]*'
+            AND c.name = 'timestamp' AND c.pk > 0",
+    )?;
+    let tables = stmt
+        .query_map(rusqlite::params![], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut sql = String::new();
+    for table in tables {
+        let Some(record_id) = table
+            .strip_prefix("record_")
+            .and_then(|id| id.parse::<i64>().ok())
+        else {
+            continue;
+        };
+
+        sql.push_str(&format!(
+            "DROP INDEX IF EXISTS idx_record_{0}_group_by;
+            ALTER TABLE record_{0} RENAME TO record_{0}_legacy;
+            CREATE TABLE record_{0} (
+                timestamp INTEGER NOT NULL,
+                group_by TEXT NOT NULL,
+                execution_time_us INTEGER NOT NULL,
+                error INTEGER DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS idx_record_{0}_timestamp ON record_{0} (timestamp);
+            CREATE INDEX IF NOT EXISTS idx_record_{0}_group_by ON record_{0} (group_by, execution_time_us);
+            INSERT INTO record_{0} (timestamp, group_by, execution_time_us, error)
+            SELECT timestamp, group_by, execution_time_us, error FROM record_{0}_legacy;
+            DROP TABLE record_{0}_legacy;
+            ",
+            record_id
+        ));
+    }
+
+    Ok(sql)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestDatabase;
+    use kodama_api::{Labels, Timestamp};
+
+    #[test]
+    fn record_timestamp_key() {
+        let database = TestDatabase::new("record-timestamp");
+        let mut kodama = database.instance();
+        let service_id = kodama.get_service_id("shop", "api").unwrap();
+
+        // a service database from before 0001_record_timestamp
+        rusqlite::Connection::open(database.path.join(format!("service-{}.db", service_id)))
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE record_1 (
+                    timestamp INTEGER PRIMARY KEY,
+                    group_by TEXT NOT NULL,
+                    execution_time_us INTEGER NOT NULL,
+                    error INTEGER DEFAULT 0
+                );
+                CREATE INDEX idx_record_1_group_by ON record_1 (group_by, execution_time_us);
+                INSERT INTO record_1 VALUES (1000, '/', 10, 0), (2000, '/a', 20, 1);",
+            )
+            .unwrap();
+
+        // opening the service migrates it, then records may share a timestamp
+        let timestamp = Timestamp { microseconds: 3000 };
+        for _ in 0..2 {
+            kodama
+                .add_record(
+                    "shop",
+                    "api",
+                    "checkout",
+                    "/",
+                    Some(timestamp.clone()),
+                    30,
+                    false,
+                    &Labels::new(),
+                )
+                .unwrap();
+        }
+        assert_eq!(kodama.get_record_id(service_id, "checkout").unwrap(), 1);
+
+        let entries = kodama
+            .record_entries(
+                "shop",
+                "api",
+                "checkout",
+                None,
+                None,
+                &[],
+                &Labels::new(),
+                None,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.group_by, entry.count, entry.errors))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![("/".to_string(), 3, 0), ("/a".to_string(), 1, 1)]
+        );
+    }
+}
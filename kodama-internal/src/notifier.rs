@@ -0,0 +1,478 @@
+use crate::{
+    alert::{Aggregation, AlertChange, AlertTarget, Comparison},
+    is_constraint_violation, is_valid_name, ApiError, Error, Kodama, Result,
+};
+use kodama_api::Timestamp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifierKind {
+    /// POST the notification as JSON to an `http://` url
+    Webhook,
+    /// Run a shell command with the notification in `KODAMA_ALERT_*`
+    /// environment variables
+    Command,
+    /// Append the notification as a JSON line to a file
+    File,
+}
+
+text_enum!(NotifierKind, "notifier kind", {
+    Webhook => "webhook",
+    Command => "command",
+    File => "file",
+});
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CreateRequest {
+    pub notifier_name: String,
+    pub kind: NotifierKind,
+    /// Url, command or file path depending on `kind`
+    pub target: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListResponse {
+    pub notifiers: Vec<Notifier>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Notifier {
+    pub id: i64,
+    pub name: String,
+    pub kind: NotifierKind,
+    pub target: String,
+}
+
+/// Deliver the changes of an alert to a notifier. While the alert keeps
+/// firing, the notification is repeated every `repeat_us` if set.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RouteRequest {
+    pub project_name: String,
+    pub service_name: String,
+    pub alert_name: String,
+    pub notifier_name: String,
+    #[serde(default)]
+    pub repeat_us: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RouteListRequest {
+    #[serde(default)]
+    pub project_name: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RouteListResponse {
+    pub routes: Vec<Route>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Route {
+    pub project_name: String,
+    pub service_name: String,
+    pub alert_name: String,
+    pub notifier_name: String,
+    pub repeat_us: Option<u64>,
+    /// Last delivered change
+    pub notified_change: Option<AlertChange>,
+    pub notified_at: Option<Timestamp>,
+}
+
+/// Alert details sent to a notifier.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AlertMessage {
+    pub project_name: String,
+    pub service_name: String,
+    pub alert_name: String,
+    pub change: AlertChange,
+    /// Whether this repeats an earlier notification of a firing alert
+    pub repeat: bool,
+    pub value: f64,
+    pub target: AlertTarget,
+    pub target_name: String,
+    pub aggregation: Aggregation,
+    pub window_us: u64,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    pub timestamp: Timestamp,
+}
+
+/// A message due on a route, see `Kodama::due_notifications`.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub notifier: Notifier,
+    pub message: AlertMessage,
+    route_id: i64,
+    history_id: i64,
+}
+
+/// Delivery state of a route.
+struct RouteState {
+    repeat_us: Option<u64>,
+    notified_history_id: i64,
+    notified_change: Option<AlertChange>,
+    notified_at: Option<Timestamp>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Delivery {
+    /// Deliver the latest change
+    Change,
+    /// Repeat the firing notification
+    Repeat,
+    /// Mark the latest change as delivered without sending it
+    Skip,
+}
+
+impl RouteState {
+    /// What to do given the latest history entry of the alert. Changes are
+    /// collapsed to the latest one, and a resolve is only sent after its
+    /// firing was.
+    fn delivery(&self, history_id: i64, change: AlertChange, now: &Timestamp) -> Option<Delivery> {
+        if history_id > self.notified_history_id {
+            return match (change, self.notified_change) {
+                (AlertChange::Resolved, Some(AlertChange::Firing)) => Some(Delivery::Change),
+                (AlertChange::Resolved, _) => Some(Delivery::Skip),
+                (AlertChange::Firing, _) => Some(Delivery::Change),
+            };
+        }
+
+        let repeat_us = self.repeat_us?;
+        let notified_at = self.notified_at.as_ref()?;
+        match self.notified_change {
+            Some(AlertChange::Firing)
+                if now.microseconds.saturating_sub(notified_at.microseconds) >= repeat_us =>
+            {
+                Some(Delivery::Repeat)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Kodama {
+    pub fn create_notifier(&self, request: &CreateRequest) -> Result<i64> {
+        let invalid = |reason: String| Err(ApiError::InvalidNotifier(reason).into());
+        if !is_valid_name(&request.notifier_name) {
+            return invalid(format!("invalid name: {:?}", request.notifier_name));
+        }
+        if request.target.trim().is_empty() {
+            return invalid(format!("missing {} target", request.kind));
+        }
+        if request.kind == NotifierKind::Webhook && !request.target.starts_with("http://") {
+            return invalid("webhook url must start with http://".to_string());
+        }
+
+        let mut stmt = self
+            .db
+            .prepare("INSERT INTO notifiers (notifier_name, kind, target) VALUES (?1, ?2, ?3)")?;
+        let notifier_id = stmt
+            .insert(rusqlite::params![
+                request.notifier_name,
+                request.kind,
+                request.target
+            ])
+            .map_err(|err| match is_constraint_violation(&err) {
+                true => ApiError::NotifierAlreadyExists(request.notifier_name.clone()).into(),
+                false => Error::from(err),
+            })?;
+        Ok(notifier_id)
+    }
+
+    pub fn notifier_list(&self) -> Result<Vec<Notifier>> {
+        let mut stmt = self.db.prepare(
+            "SELECT notifier_id, notifier_name, kind, target FROM notifiers ORDER BY notifier_name",
+        )?;
+        let notifiers = stmt
+            .query_map(rusqlite::params![], |row| {
+                Ok(Notifier {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    kind: row.get(2)?,
+                    target: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(notifiers)
+    }
+
+    /// Route an alert to a notifier, updating the repeat interval of an
+    /// existing route. A new route delivers the alert if it is firing.
+    pub fn route_alert(&self, request: &RouteRequest) -> Result<()> {
+        let service_id = self.get_service_id(&request.project_name, &request.service_name)?;
+        let alert_id: i64 = self
+            .db
+            .query_row(
+                "SELECT alert_id FROM alert_rules WHERE service_id = ?1 AND alert_name = ?2",
+                rusqlite::params![service_id, request.alert_name],
+                |row| row.get(0),
+            )
+            .map_err(|err| match err {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    ApiError::AlertNotFound(request.alert_name.clone()).into()
+                }
+                err => Error::from(err),
+            })?;
+        let notifier_id: i64 = self
+            .db
+            .query_row(
+                "SELECT notifier_id FROM notifiers WHERE notifier_name = ?1",
+                rusqlite::params![request.notifier_name],
+                |row| row.get(0),
+            )
+            .map_err(|err| match err {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    ApiError::NotifierNotFound(request.notifier_name.clone()).into()
+                }
+                err => Error::from(err),
+            })?;
+
+        self.db.execute(
+            "INSERT INTO alert_routes (alert_id, notifier_id, repeat_us) VALUES (?1, ?2, ?3)
+            ON CONFLICT (alert_id, notifier_id) DO UPDATE SET repeat_us = excluded.repeat_us",
+            rusqlite::params![alert_id, notifier_id, request.repeat_us],
+        )?;
+        Ok(())
+    }
+
+    pub fn route_list(&self, project_name: Option<&str>) -> Result<Vec<Route>> {
+        let mut stmt = self.db.prepare(
+            "SELECT p.project_name, s.service_name, a.alert_name, n.notifier_name, r.repeat_us,
+            r.notified_change, r.notified_at
+            FROM alert_routes AS r
+            JOIN notifiers AS n ON r.notifier_id = n.notifier_id
+            JOIN alert_rules AS a ON r.alert_id = a.alert_id
+            JOIN services AS s ON a.service_id = s.service_id
+            JOIN projects AS p ON s.project_id = p.project_id
+            WHERE ?1 IS NULL OR p.project_name = ?1
+            ORDER BY p.project_name, s.service_name, a.alert_name, n.notifier_name",
+        )?;
+        let routes = stmt
+            .query_map(rusqlite::params![project_name], |row| {
+                Ok(Route {
+                    project_name: row.get(0)?,
+                    service_name: row.get(1)?,
+                    alert_name: row.get(2)?,
+                    notifier_name: row.get(3)?,
+                    repeat_us: row.get(4)?,
+                    notified_change: row.get(5)?,
+                    notified_at: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(routes)
+    }
+
+    /// Notifications to deliver at `now`, based on the alert history. Each
+    /// one has to be confirmed with `mark_notified` once delivered, until
+    /// then it is returned again.
+    pub fn due_notifications(&self, now: &Timestamp) -> Result<Vec<Notification>> {
+        let mut stmt = self.db.prepare(
+            "SELECT r.route_id, r.repeat_us, r.notified_history_id, r.notified_change, r.notified_at,
+            n.notifier_id, n.notifier_name, n.kind, n.target,
+            p.project_name, s.service_name, a.alert_name, a.target, a.target_name, a.aggregation,
+            a.window_us, a.comparison, a.threshold, a.value,
+            h.history_id, h.change, h.value, h.timestamp
+            FROM alert_routes AS r
+            JOIN notifiers AS n ON r.notifier_id = n.notifier_id
+            JOIN alert_rules AS a ON r.alert_id = a.alert_id
+            JOIN services AS s ON a.service_id = s.service_id
+            JOIN projects AS p ON s.project_id = p.project_id
+            JOIN alert_history AS h ON h.history_id =
+                (SELECT MAX(history_id) FROM alert_history WHERE alert_id = a.alert_id)
+            ORDER BY r.route_id",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![])?;
+
+        let mut notifications = Vec::new();
+        while let Some(row) = rows.next()? {
+            let route_id: i64 = row.get(0)?;
+            let state = RouteState {
+                repeat_us: row.get(1)?,
+                notified_history_id: row.get(2)?,
+                notified_change: row.get(3)?,
+                notified_at: row.get(4)?,
+            };
+            let history_id: i64 = row.get(19)?;
+            let change: AlertChange = row.get(20)?;
+
+            let (repeat, value, timestamp) = match state.delivery(history_id, change, now) {
+                None => continue,
+                Some(Delivery::Skip) => {
+                    self.set_notified(route_id, history_id, change, now)?;
+                    continue;
+                }
+                Some(Delivery::Change) => (false, row.get(21)?, row.get(22)?),
+                Some(Delivery::Repeat) => {
+                    let value: Option<f64> = row.get(18)?;
+                    (true, value.unwrap_or(row.get(21)?), now.clone())
+                }
+            };
+
+            notifications.push(Notification {
+                notifier: Notifier {
+                    id: row.get(5)?,
+                    name: row.get(6)?,
+                    kind: row.get(7)?,
+                    target: row.get(8)?,
+                },
+                message: AlertMessage {
+                    project_name: row.get(9)?,
+                    service_name: row.get(10)?,
+                    alert_name: row.get(11)?,
+                    change,
+                    repeat,
+                    value,
+                    target: row.get(12)?,
+                    target_name: row.get(13)?,
+                    aggregation: row.get(14)?,
+                    window_us: row.get(15)?,
+                    comparison: row.get(16)?,
+                    threshold: row.get(17)?,
+                    timestamp,
+                },
+                route_id,
+                history_id,
+            });
+        }
+        Ok(notifications)
+    }
+
+    /// Record a notification as delivered, so it is not sent again before
+    /// its repeat interval.
+    pub fn mark_notified(&self, notification: &Notification, now: &Timestamp) -> Result<()> {
+        self.set_notified(
+            notification.route_id,
+            notification.history_id,
+            notification.message.change,
+            now,
+        )
+    }
+
+    fn set_notified(
+        &self,
+        route_id: i64,
+        history_id: i64,
+        change: AlertChange,
+        now: &Timestamp,
+    ) -> Result<()> {
+        self.db.execute(
+            "UPDATE alert_routes SET notified_history_id = ?2, notified_change = ?3, notified_at = ?4
+            WHERE route_id = ?1",
+            rusqlite::params![route_id, history_id, change, now],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{add_record_at, at, TestDatabase};
+
+    #[test]
+    fn due_and_notified() {
+        let database = TestDatabase::new("notify");
+        let mut kodama = database.with_alert("slow");
+        kodama
+            .create_notifier(&CreateRequest {
+                notifier_name: "ops".to_string(),
+                kind: NotifierKind::File,
+                target: "/dev/null".to_string(),
+            })
+            .unwrap();
+        kodama
+            .route_alert(&RouteRequest {
+                project_name: "shop".to_string(),
+                service_name: "api".to_string(),
+                alert_name: "slow".to_string(),
+                notifier_name: "ops".to_string(),
+                repeat_us: Some(100 * 1_000_000),
+            })
+            .unwrap();
+        let due = |kodama: &Kodama, seconds: u64| {
+            kodama
+                .due_notifications(&at(seconds))
+                .unwrap()
+                .into_iter()
+                .map(|notification| {
+                    let message = notification.message;
+                    (message.change, message.repeat, message.value)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mark = |kodama: &Kodama, seconds: u64| {
+            for notification in kodama.due_notifications(&at(seconds)).unwrap() {
+                kodama.mark_notified(&notification, &at(seconds)).unwrap();
+            }
+        };
+
+        // nothing is due before the alert changed
+        assert!(due(&kodama, 0).is_empty());
+        add_record_at(&mut kodama, 10, 500);
+        kodama.evaluate_alerts(&at(30)).unwrap();
+
+        // a notification stays due until it is marked as delivered
+        assert_eq!(due(&kodama, 30), [(AlertChange::Firing, false, 500.0)]);
+        assert_eq!(due(&kodama, 31), [(AlertChange::Firing, false, 500.0)]);
+        mark(&kodama, 31);
+        assert!(due(&kodama, 40).is_empty());
+
+        // repeated while firing, once the repeat interval passed
+        assert!(due(&kodama, 130).is_empty());
+        assert_eq!(due(&kodama, 131), [(AlertChange::Firing, true, 500.0)]);
+        mark(&kodama, 131);
+        assert!(due(&kodama, 200).is_empty());
+
+        add_record_at(&mut kodama, 210, 20);
+        kodama.evaluate_alerts(&at(240)).unwrap();
+        assert_eq!(due(&kodama, 240), [(AlertChange::Resolved, false, 20.0)]);
+        mark(&kodama, 240);
+        assert!(due(&kodama, 1000).is_empty());
+        let route = kodama.route_list(None).unwrap().remove(0);
+        assert_eq!(route.notified_change, Some(AlertChange::Resolved));
+    }
+
+    #[test]
+    fn delivery() {
+        let at = |microseconds| Timestamp { microseconds };
+        let mut state = RouteState {
+            repeat_us: Some(100),
+            notified_history_id: 0,
+            notified_change: None,
+            notified_at: None,
+        };
+
+        // a resolve without a delivered firing is skipped
+        assert_eq!(
+            state.delivery(1, AlertChange::Resolved, &at(0)),
+            Some(Delivery::Skip)
+        );
+        assert_eq!(
+            state.delivery(2, AlertChange::Firing, &at(0)),
+            Some(Delivery::Change)
+        );
+
+        state.notified_history_id = 2;
+        state.notified_change = Some(AlertChange::Firing);
+        state.notified_at = Some(at(1000));
+        assert_eq!(state.delivery(2, AlertChange::Firing, &at(1099)), None);
+        assert_eq!(
+            state.delivery(2, AlertChange::Firing, &at(1100)),
+            Some(Delivery::Repeat)
+        );
+        assert_eq!(
+            state.delivery(3, AlertChange::Resolved, &at(1050)),
+            Some(Delivery::Change)
+        );
+
+        state.repeat_us = None;
+        assert_eq!(state.delivery(2, AlertChange::Firing, &at(5000)), None);
+
+        state.notified_history_id = 3;
+        state.notified_change = Some(AlertChange::Resolved);
+        state.repeat_us = Some(100);
+        assert_eq!(state.delivery(3, AlertChange::Resolved, &at(5000)), None);
+        assert_eq!("file".parse(), Ok(NotifierKind::File));
+    }
+}
@@ -0,0 +1,75 @@
+/// Creates projects and services the first time data is pushed to them,
+/// instead of rejecting the data with `ServiceNotFound`. Without patterns
+/// nothing is provisioned.
+#[derive(Debug, Clone, Default)]
+pub struct AutoProvision {
+    patterns: Vec<String>,
+}
+
+impl AutoProvision {
+    /// Provision every project and service.
+    pub fn all() -> Self {
+        Self::with_patterns(["*"])
+    }
+
+    /// Only provision services whose `project/service` name matches one of
+    /// `patterns`. Patterns support `*` (any sequence) and `?` (any single
+    /// character), e.g. `web/*` or `*/api-?`.
+    pub fn with_patterns(patterns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            patterns: patterns.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn allows(&self, project_name: &str, service_name: &str) -> bool {
+        let name = format!("{}/{}", project_name, service_name);
+        self.patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` consume one more character
+                Some((star, consumed)) => {
+                    p = star + 1;
+                    n = consumed + 1;
+                    backtrack = Some((star, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let provision = AutoProvision::with_patterns(["web/*", "*/api-?"]);
+        assert!(provision.allows("web", "frontend"));
+        assert!(provision.allows("billing", "api-1"));
+        assert!(!provision.allows("billing", "api-10"));
+        assert!(!provision.allows("webb", "frontend"));
+        assert!(AutoProvision::all().allows("any", "thing"));
+        assert!(!AutoProvision::with_patterns(Vec::<String>::new()).allows("any", "thing"));
+        assert!(!AutoProvision::default().allows("any", "thing"));
+    }
+}
@@ -1,3 +1,5 @@
+use kodama_api::{Labels, Timestamp};
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct ListRequest {
     pub project_name: String,
@@ -20,6 +22,21 @@ pub struct DataRequest {
     pub project_name: String,
     pub service_name: String,
     pub record_name: String,
+    /// Only include records at or after this time
+    #[serde(default)]
+    pub from: Option<Timestamp>,
+    /// Only include records before this time
+    #[serde(default)]
+    pub to: Option<Timestamp>,
+    /// Additional percentiles to compute (e.g. 90, 99 or 99.9)
+    #[serde(default)]
+    pub percentiles: Vec<f64>,
+    /// Only include records carrying all of these labels
+    #[serde(default)]
+    pub labels: Labels,
+    /// Group by the value of this label instead of the group by value
+    #[serde(default)]
+    pub group_by_label: Option<String>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -29,7 +46,7 @@ pub struct DataResponse {
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct DataEntry {
-    /// Group by value
+    /// Group by value, or label value when grouping by label
     pub group_by: String,
     /// Total record count
     pub count: i64,
@@ -47,4 +64,102 @@ pub struct DataEntry {
     pub p50: u64,
     /// Execution time 95th percentile in microseconds
     pub p95: u64,
+    /// Execution time of each requested percentile in microseconds
+    pub percentiles: Vec<Percentile>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Percentile {
+    /// Percentile in the range (0, 100]
+    pub percentile: f64,
+    /// Execution time in microseconds
+    pub value: u64,
+}
+
+/// Zero-based index of `percentile` in `count` sorted values, using the
+/// nearest-rank method.
+pub(crate) fn percentile_rank(count: usize, percentile: f64) -> usize {
+    // the epsilon keeps e.g. 99.9% of 1000 from rounding up past rank 999
+    let rank = (percentile / 100.0 * count as f64 - 1e-9).ceil() as usize;
+    rank.clamp(1, count.max(1)) - 1
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SeriesRequest {
+    pub project_name: String,
+    pub service_name: String,
+    pub record_name: String,
+    /// Bucket width in microseconds
+    pub bucket: u64,
+    /// Only include records with this group by value
+    #[serde(default)]
+    pub group_by: Option<String>,
+    /// Only include records at or after this time
+    #[serde(default)]
+    pub from: Option<Timestamp>,
+    /// Only include records before this time
+    #[serde(default)]
+    pub to: Option<Timestamp>,
+    /// Only include records carrying all of these labels
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SeriesResponse {
+    pub entries: Vec<SeriesEntry>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SeriesEntry {
+    /// Start of the bucket
+    pub timestamp: Timestamp,
+    /// Total record count
+    pub count: i64,
+    /// Total record errors
+    pub errors: i64,
+    /// Average record execution time in microseconds
+    pub avg: u64,
+    /// Execution time 50th percentile in microseconds
+    pub p50: u64,
+    /// Execution time 95th percentile in microseconds
+    pub p95: u64,
+    /// Execution time 99th percentile in microseconds
+    pub p99: u64,
+}
+
+impl SeriesEntry {
+    /// Build a bucket entry from execution times sorted in ascending order.
+    pub(crate) fn from_sorted(bucket: u64, values: &[u64], errors: i64) -> Self {
+        let count = values.len();
+        let total: u64 = values.iter().sum();
+        let percentile = |p: f64| values[percentile_rank(count, p)];
+        Self {
+            timestamp: Timestamp {
+                microseconds: bucket,
+            },
+            count: count as i64,
+            errors,
+            avg: (total as f64 / count as f64).round() as u64,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank() {
+        assert_eq!(percentile_rank(1, 50.0), 0);
+        assert_eq!(percentile_rank(4, 50.0), 1);
+        assert_eq!(percentile_rank(5, 50.0), 2);
+        assert_eq!(percentile_rank(100, 95.0), 94);
+        assert_eq!(percentile_rank(1000, 99.9), 998);
+        assert_eq!(percentile_rank(1000, 100.0), 999);
+        assert_eq!(percentile_rank(3, 0.1), 0);
+    }
 }
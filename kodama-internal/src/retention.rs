@@ -0,0 +1,77 @@
+/// Set (or with `keep_us` of `None`, remove) the retention of a project, a
+/// service or a single record. The most specific policy wins.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SetRequest {
+    pub project_name: String,
+    #[serde(default)]
+    pub service_name: Option<String>,
+    #[serde(default)]
+    pub record_name: Option<String>,
+    /// How long samples are kept, in microseconds
+    #[serde(default)]
+    pub keep_us: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SetResponse {}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListRequest {
+    #[serde(default)]
+    pub project_name: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListResponse {
+    pub policies: Vec<RetentionPolicy>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RetentionPolicy {
+    pub project_name: String,
+    pub service_name: Option<String>,
+    pub record_name: Option<String>,
+    pub keep_us: u64,
+}
+
+/// Policies keyed by ids, used while pruning.
+pub(crate) struct Policies(pub Vec<(i64, Option<i64>, Option<i64>, u64)>);
+
+impl Policies {
+    /// Retention of a service, or of a record within it if `record_id` is
+    /// given, falling back to the policy of the enclosing scope.
+    pub fn keep_us(&self, project_id: i64, service_id: i64, record_id: Option<i64>) -> Option<u64> {
+        let find = |service_id: Option<i64>, record_id: Option<i64>| {
+            self.0
+                .iter()
+                .find(|policy| {
+                    policy.0 == project_id && policy.1 == service_id && policy.2 == record_id
+                })
+                .map(|policy| policy.3)
+        };
+
+        record_id
+            .and_then(|record_id| find(Some(service_id), Some(record_id)))
+            .or_else(|| find(Some(service_id), None))
+            .or_else(|| find(None, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_policy() {
+        let policies = Policies(vec![
+            (1, None, None, 30),
+            (1, Some(2), None, 7),
+            (1, Some(2), Some(3), 1),
+        ]);
+        assert_eq!(policies.keep_us(1, 2, Some(3)), Some(1));
+        assert_eq!(policies.keep_us(1, 2, Some(4)), Some(7));
+        assert_eq!(policies.keep_us(1, 2, None), Some(7));
+        assert_eq!(policies.keep_us(1, 5, Some(3)), Some(30));
+        assert_eq!(policies.keep_us(6, 2, Some(3)), None);
+    }
+}
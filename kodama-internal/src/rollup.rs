@@ -0,0 +1,921 @@
+use crate::{
+    record::{DataEntry, Percentile, SeriesEntry},
+    sketch::Sketch,
+    Kodama, Result, Service,
+};
+use kodama_api::Timestamp;
+use rusqlite::OptionalExtension;
+use std::collections::{BTreeMap, HashMap};
+
+/// Rollup resolutions. Minute rollups are computed from raw records, hour
+/// rollups from minutes and day rollups from hours.
+pub(crate) const MINUTE: u64 = 60 * 1000 * 1000;
+pub(crate) const HOUR: u64 = 60 * MINUTE;
+pub(crate) const DAY: u64 = 24 * HOUR;
+
+/// Minute buckets are only rolled up once they ended this long ago, to give
+/// late records a chance to arrive.
+const ROLLUP_DELAY: u64 = MINUTE;
+
+/// Rollups of one resolution, up to `end`.
+struct RollupChunk {
+    resolution: u64,
+    end: u64,
+    rows: Vec<(u64, String, Aggregate)>,
+}
+
+impl RollupChunk {
+    fn new(resolution: u64, end: u64, buckets: HashMap<(u64, String), Aggregate>) -> Self {
+        let rows = buckets
+            .into_iter()
+            .map(|((bucket, group_by), aggregate)| (bucket, group_by, aggregate))
+            .collect();
+        Self {
+            resolution,
+            end,
+            rows,
+        }
+    }
+}
+
+/// Summary of the records of a single group_by within a bucket.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Aggregate {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    pub errors: u64,
+    pub sketch: Sketch,
+}
+
+impl Aggregate {
+    pub fn add(&mut self, execution_time: u64, error: bool) {
+        self.min = if self.count == 0 {
+            execution_time
+        } else {
+            self.min.min(execution_time)
+        };
+        self.max = self.max.max(execution_time);
+        self.count += 1;
+        self.sum += execution_time;
+        self.errors += error as u64;
+        self.sketch.add(execution_time);
+    }
+
+    pub fn merge(&mut self, other: &Aggregate) {
+        if other.count == 0 {
+            return;
+        }
+
+        self.min = if self.count == 0 {
+            other.min
+        } else {
+            self.min.min(other.min)
+        };
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.sum += other.sum;
+        self.errors += other.errors;
+        self.sketch.merge(&other.sketch);
+    }
+
+    /// Percentile from the sketch, kept within the exact min and max.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        self.sketch.percentile(percentile).clamp(self.min, self.max)
+    }
+
+    pub fn avg(&self) -> u64 {
+        (self.sum as f64 / self.count.max(1) as f64).round() as u64
+    }
+
+    fn from_row(row: &rusqlite::Row, offset: usize) -> rusqlite::Result<Self> {
+        Ok(Self {
+            count: row.get(offset)?,
+            sum: row.get(offset + 1)?,
+            min: row.get(offset + 2)?,
+            max: row.get(offset + 3)?,
+            errors: row.get(offset + 4)?,
+            sketch: row.get(offset + 5)?,
+        })
+    }
+}
+
+/// `(resolution, watermark)` pairs, rollup buckets before the watermark have
+/// been computed.
+pub(crate) type Watermarks = Vec<(u64, u64)>;
+
+fn floor(value: u64, resolution: u64) -> u64 {
+    value / resolution * resolution
+}
+
+fn ceil(value: u64, resolution: u64) -> u64 {
+    value.div_ceil(resolution) * resolution
+}
+
+/// Split `[from, to)` into `(resolution, from, to)` ranges of rollup buckets,
+/// using the coarsest resolution whose buckets fit and have been rolled up.
+/// `resolutions` holds `(resolution, watermark)` pairs from coarsest to
+/// finest, where buckets before the watermark are available. Buckets of the
+/// finest resolution are included if they start within the range.
+pub(crate) fn rollup_segments(
+    from: u64,
+    to: u64,
+    resolutions: &[(u64, u64)],
+) -> Vec<(u64, u64, u64)> {
+    fn cover(from: u64, to: u64, resolutions: &[(u64, u64)], output: &mut Vec<(u64, u64, u64)>) {
+        let Some(((resolution, watermark), finer)) = resolutions.split_first() else {
+            return;
+        };
+
+        if finer.is_empty() {
+            let end = to.min(*watermark);
+            if from < end {
+                output.push((*resolution, from, end));
+            }
+            return;
+        }
+
+        let start = ceil(from, *resolution);
+        let end = floor(to.min(*watermark), *resolution);
+        if start < end {
+            cover(from, start, finer, output);
+            output.push((*resolution, start, end));
+            cover(end, to, finer, output);
+        } else {
+            cover(from, to, finer, output);
+        }
+    }
+
+    let mut output = Vec::new();
+    cover(from, to, resolutions, &mut output);
+    output
+}
+
+/// Lower the rolled up rowid of a record to the highest rowid left in its
+/// table after deleting records, as SQLite reuses the rowids above it.
+pub(crate) fn clamp_rollup_rows(db: &rusqlite::Connection, record_id: i64) -> Result<()> {
+    db.execute(
+        &format!(
+            "UPDATE rollup_rows SET max_rowid = MIN(max_rowid, (SELECT IFNULL(MAX(rowid), 0) FROM record_{}))
+            WHERE record_id = ?1",
+            record_id
+        ),
+        rusqlite::params![record_id],
+    )?;
+    Ok(())
+}
+
+impl Service {
+    /// `(resolution, watermark)` of every resolution rolled up so far, from
+    /// coarsest to finest.
+    pub(crate) fn rollup_watermarks(&self, record_id: i64) -> Result<Watermarks> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT resolution, watermark FROM rollup_state WHERE record_id = ?1 ORDER BY resolution DESC",
+        )?;
+        let watermarks = stmt
+            .query_map(rusqlite::params![record_id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(watermarks)
+    }
+
+    /// Highest rowid of the raw records included in the rollups of a record.
+    pub(crate) fn rolled_up_rowid(&self, record_id: i64) -> Result<Option<i64>> {
+        let rowid = self
+            .db
+            .query_row(
+                "SELECT max_rowid FROM rollup_rows WHERE record_id = ?1",
+                rusqlite::params![record_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(rowid)
+    }
+
+    /// Start of the raw records used by queries, everything before it is read
+    /// from rollups. `None` if no raw records have been pruned yet, so there
+    /// is no need to read from rollups.
+    pub(crate) fn rollup_cutoff(&self, record_id: i64) -> Result<Option<(u64, Watermarks)>> {
+        let watermarks = self.rollup_watermarks(record_id)?;
+        if watermarks.is_empty() {
+            return Ok(None);
+        }
+
+        let oldest: Option<u64> = self.db.query_row(
+            &format!("SELECT MIN(timestamp) FROM record_{}", record_id),
+            rusqlite::params![],
+            |row| row.get(0),
+        )?;
+        let cutoff = match oldest {
+            Some(oldest) => {
+                let pruned: bool = self.db.query_row(
+                    &format!(
+                        "SELECT EXISTS(SELECT 1 FROM rollup_{} WHERE resolution = ?1 AND timestamp < ?2)",
+                        record_id
+                    ),
+                    rusqlite::params![MINUTE, floor(oldest, MINUTE)],
+                    |row| row.get(0),
+                )?;
+                if !pruned {
+                    return Ok(None);
+                }
+                // the minute of the oldest record may not be rolled up yet
+                let minute = watermarks
+                    .iter()
+                    .find(|(resolution, _)| *resolution == MINUTE)
+                    .map_or(0, |(_, watermark)| *watermark);
+                ceil(oldest, MINUTE).min(minute)
+            }
+            // with all raw records gone, whatever has been rolled up is all there is
+            None => i64::MAX as u64,
+        };
+        Ok(Some((cutoff, watermarks)))
+    }
+
+    /// `record_entries` for a range starting before `cutoff`: rollups up to
+    /// `cutoff` and raw records after it.
+    pub(crate) fn record_entries_with_rollups(
+        &self,
+        record_id: i64,
+        from: u64,
+        to: Option<&Timestamp>,
+        cutoff: u64,
+        watermarks: &[(u64, u64)],
+        percentiles: &[f64],
+    ) -> Result<Vec<DataEntry>> {
+        let to = to.map(|to| to.microseconds);
+        let segments = rollup_segments(from, to.unwrap_or(cutoff).min(cutoff), watermarks);
+
+        let mut groups: BTreeMap<String, Aggregate> = BTreeMap::new();
+        self.rollup_aggregates(record_id, &segments, None, |_, group_by, aggregate| {
+            groups.entry(group_by).or_default().merge(&aggregate);
+        })?;
+        self.raw_aggregates(
+            record_id,
+            None,
+            cutoff,
+            to,
+            None,
+            |_, group_by, execution_time, error| {
+                groups
+                    .entry(group_by)
+                    .or_default()
+                    .add(execution_time, error);
+            },
+        )?;
+
+        let entries = groups
+            .into_iter()
+            .map(|(group_by, aggregate)| DataEntry {
+                group_by,
+                count: aggregate.count as i64,
+                errors: aggregate.errors as i64,
+                execution_time: aggregate.sum,
+                min: aggregate.min,
+                max: aggregate.max,
+                avg: aggregate.avg(),
+                p50: aggregate.percentile(50.0),
+                p95: aggregate.percentile(95.0),
+                percentiles: percentiles
+                    .iter()
+                    .map(|percentile| Percentile {
+                        percentile: *percentile,
+                        value: aggregate.percentile(*percentile),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    /// `record_series` for a range starting before `cutoff`. Rollups are read
+    /// at the coarsest resolution dividing `bucket_us`, or per minute if none
+    /// does.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn record_series_with_rollups(
+        &self,
+        record_id: i64,
+        bucket_us: u64,
+        group_by: Option<&str>,
+        from: u64,
+        to: Option<&Timestamp>,
+        cutoff: u64,
+        watermarks: &[(u64, u64)],
+    ) -> Result<Vec<SeriesEntry>> {
+        let mut resolutions = watermarks
+            .iter()
+            .copied()
+            .filter(|(resolution, _)| bucket_us.is_multiple_of(*resolution))
+            .collect::<Vec<_>>();
+        if resolutions.is_empty() {
+            resolutions.extend(
+                watermarks
+                    .iter()
+                    .copied()
+                    .filter(|(resolution, _)| *resolution == MINUTE),
+            );
+        }
+
+        let to = to.map(|to| to.microseconds);
+        let segments = rollup_segments(from, to.unwrap_or(cutoff).min(cutoff), &resolutions);
+
+        let mut buckets: BTreeMap<u64, Aggregate> = BTreeMap::new();
+        self.rollup_aggregates(record_id, &segments, group_by, |timestamp, _, aggregate| {
+            buckets
+                .entry(floor(timestamp, bucket_us))
+                .or_default()
+                .merge(&aggregate);
+        })?;
+        self.raw_aggregates(
+            record_id,
+            group_by,
+            cutoff,
+            to,
+            None,
+            |timestamp, _, execution_time, error| {
+                buckets
+                    .entry(floor(timestamp, bucket_us))
+                    .or_default()
+                    .add(execution_time, error);
+            },
+        )?;
+
+        let entries = buckets
+            .into_iter()
+            .map(|(bucket, aggregate)| SeriesEntry {
+                timestamp: Timestamp {
+                    microseconds: bucket,
+                },
+                count: aggregate.count as i64,
+                errors: aggregate.errors as i64,
+                avg: aggregate.avg(),
+                p50: aggregate.percentile(50.0),
+                p95: aggregate.percentile(95.0),
+                p99: aggregate.percentile(99.0),
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    /// Scan raw records in `[from, to)` as `(timestamp, group_by,
+    /// execution_time, error)`, up to `max_rowid` if set.
+    fn raw_aggregates(
+        &self,
+        record_id: i64,
+        group_by: Option<&str>,
+        from: u64,
+        to: Option<u64>,
+        max_rowid: Option<i64>,
+        mut func: impl FnMut(u64, String, u64, bool),
+    ) -> Result<()> {
+        if to.is_some_and(|to| to <= from) {
+            return Ok(());
+        }
+
+        let mut stmt = self.db.prepare_cached(&format!(
+            "SELECT timestamp, group_by, execution_time_us, error
+            FROM record_{}
+            WHERE (?1 IS NULL OR group_by = ?1) AND timestamp >= ?2 AND (?3 IS NULL OR timestamp < ?3)
+            AND (?4 IS NULL OR rowid <= ?4)",
+            record_id
+        ))?;
+        let mut rows = stmt.query(rusqlite::params![group_by, from, to, max_rowid])?;
+        while let Some(row) = rows.next()? {
+            func(
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, i64>(3)? > 0,
+            );
+        }
+        Ok(())
+    }
+
+    /// Read the rollups of `segments` as `(timestamp, group_by, aggregate)`.
+    pub(crate) fn rollup_aggregates(
+        &self,
+        record_id: i64,
+        segments: &[(u64, u64, u64)],
+        group_by: Option<&str>,
+        mut func: impl FnMut(u64, String, Aggregate),
+    ) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "SELECT timestamp, group_by, count, sum, min, max, errors, sketch
+            FROM rollup_{}
+            WHERE resolution = ?1 AND timestamp >= ?2 AND timestamp < ?3 AND (?4 IS NULL OR group_by = ?4)",
+            record_id
+        ))?;
+        for (resolution, from, to) in segments {
+            let mut rows = stmt.query(rusqlite::params![resolution, from, to, group_by])?;
+            while let Some(row) = rows.next()? {
+                func(row.get(0)?, row.get(1)?, Aggregate::from_row(row, 2)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Roll up every complete bucket of a record that hasn't been yet,
+    /// returning the number of rollup rows written. Rollups are computed in
+    /// chunks from a read snapshot and each chunk is written in a short
+    /// transaction, so ingest is never blocked for long.
+    pub(crate) fn rollup_record(&self, record_id: i64, now: u64) -> Result<u64> {
+        // records inserted from here on are left to the next run
+        let max_rowid: i64 = self.db.query_row(
+            &format!("SELECT IFNULL(MAX(rowid), 0) FROM record_{}", record_id),
+            rusqlite::params![],
+            |row| row.get(0),
+        )?;
+
+        let mut written = self.write_immediate(|| self.merge_late_records(record_id, max_rowid))?;
+        while let Some(chunk) = self.next_rollup_chunk(record_id, now, max_rowid)? {
+            self.write_immediate(|| {
+                chunk
+                    .rows
+                    .iter()
+                    .try_for_each(|(bucket, group_by, aggregate)| {
+                        self.write_rollup(record_id, chunk.resolution, *bucket, group_by, aggregate)
+                    })?;
+                self.set_rollup_watermark(record_id, chunk.resolution, chunk.end)
+            })?;
+            written += chunk.rows.len() as u64;
+        }
+        Ok(written)
+    }
+
+    /// Run `func` in a write transaction, rolling back if it fails.
+    fn write_immediate<T>(&self, func: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.db.execute_batch("BEGIN IMMEDIATE;")?;
+        match func() {
+            Ok(value) => {
+                self.db.execute_batch("COMMIT;")?;
+                Ok(value)
+            }
+            Err(err) => {
+                self.db.execute_batch("ROLLBACK;")?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Merge the records up to `max_rowid` that arrived after their minute
+    /// was rolled up into the minute rollups, and rewind the hour and day
+    /// watermarks so their buckets are computed again. Returns the number of
+    /// rollup rows written.
+    fn merge_late_records(&self, record_id: i64, max_rowid: i64) -> Result<u64> {
+        let watermarks = self
+            .rollup_watermarks(record_id)?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        let mut buckets: HashMap<(u64, String), Aggregate> = HashMap::new();
+        if let (Some(watermark), Some(rolled_up)) =
+            (watermarks.get(&MINUTE), self.rolled_up_rowid(record_id)?)
+        {
+            let mut stmt = self.db.prepare_cached(&format!(
+                "SELECT timestamp, group_by, execution_time_us, error
+                FROM record_{}
+                WHERE rowid > ?1 AND rowid <= ?2 AND timestamp < ?3",
+                record_id
+            ))?;
+            let mut rows = stmt.query(rusqlite::params![rolled_up, max_rowid, watermark])?;
+            while let Some(row) = rows.next()? {
+                buckets
+                    .entry((floor(row.get(0)?, MINUTE), row.get(1)?))
+                    .or_default()
+                    .add(row.get(2)?, row.get::<_, i64>(3)? > 0);
+            }
+        }
+
+        for ((bucket, group_by), late) in &buckets {
+            let mut aggregate = late.clone();
+            self.rollup_aggregates(
+                record_id,
+                &[(MINUTE, *bucket, bucket + MINUTE)],
+                Some(group_by),
+                |_, _, rolled_up| aggregate.merge(&rolled_up),
+            )?;
+            self.write_rollup(record_id, MINUTE, *bucket, group_by, &aggregate)?;
+        }
+
+        if let Some(oldest) = buckets.keys().map(|(bucket, _)| *bucket).min() {
+            for resolution in [HOUR, DAY] {
+                let start = floor(oldest, resolution);
+                if watermarks
+                    .get(&resolution)
+                    .is_some_and(|watermark| start < *watermark)
+                {
+                    self.set_rollup_watermark(record_id, resolution, start)?;
+                }
+            }
+        }
+
+        self.db.execute(
+            "INSERT OR REPLACE INTO rollup_rows (record_id, max_rowid) VALUES (?1, ?2)",
+            rusqlite::params![record_id, max_rowid],
+        )?;
+        Ok(buckets.len() as u64)
+    }
+
+    /// Compute the next chunk of rollups, finest resolution first. Minute
+    /// rollups only include raw records up to `max_rowid`.
+    fn next_rollup_chunk(
+        &self,
+        record_id: i64,
+        now: u64,
+        max_rowid: i64,
+    ) -> Result<Option<RollupChunk>> {
+        let watermarks = self
+            .rollup_watermarks(record_id)?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        // minute rollups from raw records
+        let start = match watermarks.get(&MINUTE) {
+            Some(watermark) => Some(*watermark),
+            None => self
+                .db
+                .query_row(
+                    &format!(
+                        "SELECT MIN(timestamp) FROM record_{} WHERE rowid <= ?1",
+                        record_id
+                    ),
+                    rusqlite::params![max_rowid],
+                    |row| row.get::<_, Option<u64>>(0),
+                )?
+                .map(|oldest| floor(oldest, MINUTE)),
+        };
+        let Some(start) = start else {
+            return Ok(None);
+        };
+        let complete = floor(now.saturating_sub(ROLLUP_DELAY), MINUTE);
+        if start < complete {
+            let mut end = complete.min(start + HOUR);
+            let mut buckets: HashMap<(u64, String), Aggregate> = HashMap::new();
+            self.raw_aggregates(
+                record_id,
+                None,
+                start,
+                Some(end),
+                Some(max_rowid),
+                |timestamp, group_by, execution_time, error| {
+                    buckets
+                        .entry((floor(timestamp, MINUTE), group_by))
+                        .or_default()
+                        .add(execution_time, error);
+                },
+            )?;
+
+            if buckets.is_empty() {
+                // skip ahead to the next record instead of walking the gap
+                let next: Option<u64> = self.db.query_row(
+                    &format!(
+                        "SELECT MIN(timestamp) FROM record_{} WHERE timestamp >= ?1 AND rowid <= ?2",
+                        record_id
+                    ),
+                    rusqlite::params![end, max_rowid],
+                    |row| row.get(0),
+                )?;
+                end = next.map_or(complete, |next| floor(next, MINUTE).clamp(end, complete));
+            }
+            return Ok(Some(RollupChunk::new(MINUTE, end, buckets)));
+        }
+
+        // coarser rollups from the next finer resolution
+        let mut finer = (MINUTE, start);
+        for (resolution, chunk) in [(HOUR, DAY), (DAY, 30 * DAY)] {
+            let start = match watermarks.get(&resolution) {
+                Some(watermark) => *watermark,
+                None => {
+                    let oldest: Option<u64> = self.db.query_row(
+                        &format!(
+                            "SELECT MIN(timestamp) FROM rollup_{} WHERE resolution = ?1",
+                            record_id
+                        ),
+                        rusqlite::params![finer.0],
+                        |row| row.get(0),
+                    )?;
+                    match oldest {
+                        Some(oldest) => floor(oldest, resolution),
+                        None => return Ok(None),
+                    }
+                }
+            };
+
+            let end = floor(finer.1, resolution).min(start + chunk);
+            if start < end {
+                let mut buckets: HashMap<(u64, String), Aggregate> = HashMap::new();
+                self.rollup_aggregates(
+                    record_id,
+                    &[(finer.0, start, end)],
+                    None,
+                    |timestamp, group_by, aggregate| {
+                        buckets
+                            .entry((floor(timestamp, resolution), group_by))
+                            .or_default()
+                            .merge(&aggregate);
+                    },
+                )?;
+                return Ok(Some(RollupChunk::new(resolution, end, buckets)));
+            }
+            finer = (resolution, start);
+        }
+
+        Ok(None)
+    }
+
+    fn write_rollup(
+        &self,
+        record_id: i64,
+        resolution: u64,
+        bucket: u64,
+        group_by: &str,
+        aggregate: &Aggregate,
+    ) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO rollup_{}
+            (resolution, timestamp, group_by, count, sum, min, max, errors, sketch)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            record_id
+        ))?;
+        stmt.execute(rusqlite::params![
+            resolution,
+            bucket,
+            group_by,
+            aggregate.count,
+            aggregate.sum,
+            aggregate.min,
+            aggregate.max,
+            aggregate.errors,
+            aggregate.sketch,
+        ])?;
+        Ok(())
+    }
+
+    fn set_rollup_watermark(&self, record_id: i64, resolution: u64, watermark: u64) -> Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO rollup_state (record_id, resolution, watermark) VALUES (?1, ?2, ?3)",
+            rusqlite::params![record_id, resolution, watermark],
+        )?;
+        Ok(())
+    }
+}
+
+impl Kodama {
+    /// Roll up the records of every service up to `now`, returning the number
+    /// of rollup rows written. A failing service or record is logged and
+    /// skipped, and services without a database are skipped.
+    pub fn rollup(&self, now: &Timestamp) -> Result<u64> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT service_id, record_id FROM records")?;
+        let mut records: HashMap<i64, Vec<i64>> = HashMap::new();
+        for row in stmt.query_map(rusqlite::params![], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+        })? {
+            let (service_id, record_id) = row?;
+            records.entry(service_id).or_default().push(record_id);
+        }
+
+        let mut written = 0;
+        for (service_id, record_ids) in records {
+            let service = match Service::open_existing(&self.database_path, service_id) {
+                Ok(Some(service)) => service,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::error!(
+                        "unable to open service {} for rollup: {:?}",
+                        service_id,
+                        err
+                    );
+                    continue;
+                }
+            };
+            for record_id in record_ids {
+                match service.rollup_record(record_id, now.microseconds) {
+                    Ok(count) => written += count,
+                    Err(err) => tracing::error!(
+                        "unable to roll up service {} record {}: {:?}",
+                        service_id,
+                        record_id,
+                        err
+                    ),
+                }
+            }
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::TestDatabase, ApiError, Error};
+    use kodama_api::Labels;
+
+    fn at(minutes: u64, seconds: u64) -> Timestamp {
+        Timestamp {
+            microseconds: minutes * MINUTE + seconds * 1_000_000,
+        }
+    }
+
+    fn add(kodama: &mut Kodama, service_name: &str, group_by: &str, timestamp: Timestamp) {
+        kodama
+            .add_record(
+                "shop",
+                service_name,
+                "checkout",
+                group_by,
+                Some(timestamp),
+                10,
+                false,
+                &Labels::new(),
+            )
+            .unwrap();
+    }
+
+    /// `(timestamp, group_by, count)` of the rollups of a resolution.
+    fn rollups(service: &Service, record_id: i64, resolution: u64) -> Vec<(u64, String, u64)> {
+        let mut rollups = Vec::new();
+        service
+            .rollup_aggregates(
+                record_id,
+                &[(resolution, 0, u64::MAX >> 1)],
+                None,
+                |timestamp, group_by, aggregate| {
+                    rollups.push((timestamp, group_by, aggregate.count))
+                },
+            )
+            .unwrap();
+        rollups.sort();
+        rollups
+    }
+
+    #[test]
+    fn rollup_records() {
+        let database = TestDatabase::new("rollup");
+        let mut kodama = database.instance();
+        kodama.create_service("shop", "web", "").unwrap();
+        for timestamp in [at(1, 10), at(1, 20), at(61, 0)] {
+            add(&mut kodama, "api", "/", timestamp);
+        }
+        add(&mut kodama, "api", "/a", at(2, 0));
+        add(&mut kodama, "web", "/", at(1, 0));
+        let api_id = kodama.get_service_id("shop", "api").unwrap();
+        let record_id = kodama.get_record_id(api_id, "checkout").unwrap();
+        let web_id = kodama.get_service_id("shop", "web").unwrap();
+        let web_record_id = kodama.get_record_id(web_id, "checkout").unwrap();
+        drop(kodama);
+
+        let service_db = |service_id: i64| {
+            rusqlite::Connection::open(database.path.join(format!("service-{}.db", service_id)))
+                .unwrap()
+        };
+        // a database from before rollups is migrated before rolling up
+        service_db(api_id)
+            .execute_batch(&format!(
+                "DROP TABLE rollup_{};
+                DROP TABLE rollup_state;
+                DROP TABLE rollup_rows;
+                DELETE FROM migrations WHERE version IN ('0002_rollups', '0006_rollup_rows');",
+                record_id
+            ))
+            .unwrap();
+        // a broken service does not stop the others from being rolled up
+        service_db(web_id)
+            .execute_batch(&format!("DROP TABLE record_{};", web_record_id))
+            .unwrap();
+
+        let kodama = database.instance();
+        let now = at(180, 0);
+        assert_eq!(kodama.rollup(&now).unwrap(), 6);
+        let service = Service::open_existing(&kodama.database_path, api_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            service.rollup_watermarks(record_id).unwrap(),
+            vec![(HOUR, 2 * HOUR), (MINUTE, 179 * MINUTE)]
+        );
+        assert_eq!(
+            rollups(&service, record_id, HOUR),
+            vec![
+                (0, "/".to_string(), 2),
+                (0, "/a".to_string(), 1),
+                (HOUR, "/".to_string(), 1),
+            ]
+        );
+
+        // a record arriving after its minute was rolled up is merged into it,
+        // and the hour is computed again
+        let mut kodama = kodama;
+        add(&mut kodama, "api", "/", at(1, 30));
+        add(&mut kodama, "api", "/", at(179, 30));
+        assert_eq!(kodama.rollup(&now).unwrap(), 4);
+        assert_eq!(
+            rollups(&service, record_id, MINUTE),
+            vec![
+                (MINUTE, "/".to_string(), 3),
+                (2 * MINUTE, "/a".to_string(), 1),
+                (61 * MINUTE, "/".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            rollups(&service, record_id, HOUR)[0],
+            (0, "/".to_string(), 3)
+        );
+        assert_eq!(
+            service.rollup_watermarks(record_id).unwrap(),
+            vec![(HOUR, 2 * HOUR), (MINUTE, 179 * MINUTE)]
+        );
+        // nothing is merged twice
+        assert_eq!(kodama.rollup(&now).unwrap(), 0);
+
+        // once pruned, queries read rollups and the remaining raw records
+        kodama
+            .set_retention("shop", None, None, Some(HOUR))
+            .unwrap();
+        kodama.prune(&now).unwrap();
+        let raw: i64 = service
+            .db
+            .query_row(
+                &format!("SELECT COUNT(*) FROM record_{}", record_id),
+                rusqlite::params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(raw, 1);
+        let (cutoff, watermarks) = service.rollup_cutoff(record_id).unwrap().unwrap();
+        let entries = service
+            .record_entries_with_rollups(record_id, 0, None, cutoff, &watermarks, &[])
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.group_by, entry.count))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![("/".to_string(), 5), ("/a".to_string(), 1)]);
+
+        // rollups have no labels, label queries must start at the cutoff
+        let filter = Labels::from([("region".to_string(), "eu".to_string())]);
+        let refused = |result: Result<usize>| {
+            matches!(
+                result,
+                Err(Error::ApiError(ApiError::InvalidLabelRange(at))) if at == cutoff
+            )
+        };
+        let entries = |from: Option<&Timestamp>, filter: &Labels, label: Option<&str>| {
+            service
+                .record_entries(record_id, from, None, &[], filter, label)
+                .map(|entries| entries.len())
+        };
+        let series = |from: Option<&Timestamp>| {
+            service
+                .record_series(record_id, HOUR, None, from, None, &filter)
+                .map(|entries| entries.len())
+        };
+        let from = Timestamp {
+            microseconds: cutoff,
+        };
+        assert!(refused(entries(None, &filter, None)));
+        assert!(refused(entries(None, &Labels::new(), Some("region"))));
+        assert!(refused(series(None)));
+        assert_eq!(entries(Some(&from), &filter, None).unwrap(), 0);
+        assert_eq!(
+            entries(Some(&from), &Labels::new(), Some("region")).unwrap(),
+            1
+        );
+        assert_eq!(series(Some(&from)).unwrap(), 0);
+    }
+
+    #[test]
+    fn segments() {
+        let all = [(DAY, u64::MAX), (HOUR, u64::MAX), (MINUTE, u64::MAX)];
+
+        // whole days, plus the hours and minutes around them
+        let from = DAY - HOUR - 5 * MINUTE;
+        let to = 3 * DAY + HOUR + 30 * MINUTE;
+        assert_eq!(
+            rollup_segments(from, to, &all),
+            vec![
+                (MINUTE, from, DAY - HOUR),
+                (HOUR, DAY - HOUR, DAY),
+                (DAY, DAY, 3 * DAY),
+                (HOUR, 3 * DAY, 3 * DAY + HOUR),
+                (MINUTE, 3 * DAY + HOUR, to),
+            ]
+        );
+
+        // days haven't been rolled up past the second day
+        let partial = [(DAY, 2 * DAY), (HOUR, u64::MAX), (MINUTE, u64::MAX)];
+        assert_eq!(
+            rollup_segments(DAY, 3 * DAY, &partial),
+            vec![(DAY, DAY, 2 * DAY), (HOUR, 2 * DAY, 3 * DAY)]
+        );
+
+        // only minutes, limited by their watermark
+        assert_eq!(
+            rollup_segments(0, HOUR, &[(MINUTE, 10 * MINUTE)]),
+            vec![(MINUTE, 0, 10 * MINUTE)]
+        );
+        assert!(rollup_segments(HOUR, HOUR, &all).is_empty());
+    }
+}
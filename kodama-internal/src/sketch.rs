@@ -0,0 +1,145 @@
+use crate::record::percentile_rank;
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    ToSql,
+};
+use std::collections::BTreeMap;
+
+/// Relative error of the values returned by `Sketch::percentile`.
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// A mergeable percentile sketch over execution times. Values are counted in
+/// logarithmically sized bins, so any percentile is within 1% of the exact
+/// value and sketches of different time ranges can simply be added up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Sketch {
+    zero: u64,
+    bins: BTreeMap<i32, u64>,
+}
+
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+impl Sketch {
+    pub fn add(&mut self, value: u64) {
+        if value == 0 {
+            self.zero += 1;
+        } else {
+            let bin = ((value as f64).ln() / gamma().ln()).ceil() as i32;
+            *self.bins.entry(bin).or_default() += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Sketch) {
+        self.zero += other.zero;
+        for (bin, count) in &other.bins {
+            *self.bins.entry(*bin).or_default() += count;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.zero + self.bins.values().sum::<u64>()
+    }
+
+    /// Nearest-rank `percentile` in the range (0, 100].
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let rank = percentile_rank(self.count() as usize, percentile) as u64;
+        if rank < self.zero {
+            return 0;
+        }
+
+        let mut position = self.zero;
+        for (bin, count) in &self.bins {
+            position += count;
+            if position > rank {
+                // the point within the bin with the same relative error to
+                // both of its bounds
+                let gamma = gamma();
+                return (2.0 * gamma.powi(*bin) / (gamma + 1.0)).round() as u64;
+            }
+        }
+        0
+    }
+
+    /// Encoded as the zero count followed by `(bin, count)` pairs, all little
+    /// endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.bins.len() * 12);
+        bytes.extend_from_slice(&self.zero.to_le_bytes());
+        for (bin, count) in &self.bins {
+            bytes.extend_from_slice(&bin.to_le_bytes());
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (zero, mut rest) = bytes.split_first_chunk::<8>()?;
+        let mut sketch = Self {
+            zero: u64::from_le_bytes(*zero),
+            bins: BTreeMap::new(),
+        };
+        while !rest.is_empty() {
+            let (bin, tail) = rest.split_first_chunk::<4>()?;
+            let (count, tail) = tail.split_first_chunk::<8>()?;
+            sketch
+                .bins
+                .insert(i32::from_le_bytes(*bin), u64::from_le_bytes(*count));
+            rest = tail;
+        }
+        Some(sketch)
+    }
+}
+
+impl ToSql for Sketch {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Owned(rusqlite::types::Value::Blob(
+            self.to_bytes(),
+        )))
+    }
+}
+
+impl FromSql for Sketch {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Self::from_bytes(value.as_blob()?)
+            .ok_or_else(|| FromSqlError::Other("invalid sketch".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut first = Sketch::default();
+        let mut second = Sketch::default();
+        for value in 0..=1000u64 {
+            if value % 2 == 0 {
+                first.add(value * 100);
+            } else {
+                second.add(value * 100);
+            }
+        }
+        first.merge(&second);
+        assert_eq!(first.count(), 1001);
+
+        for (percentile, exact) in [(50.0, 50000.0), (95.0, 95000.0), (99.9, 99900.0)] {
+            let value = first.percentile(percentile) as f64;
+            assert!(
+                (value - exact).abs() <= exact * RELATIVE_ACCURACY,
+                "p{} = {}, expected {}",
+                percentile,
+                value,
+                exact
+            );
+        }
+        assert_eq!(first.percentile(0.01), 0);
+
+        let decoded = Sketch::from_bytes(&first.to_bytes()).unwrap();
+        assert_eq!(decoded, first);
+        assert!(Sketch::from_bytes(&[0; 7]).is_none());
+        assert!(Sketch::from_bytes(&[0; 10]).is_none());
+    }
+}
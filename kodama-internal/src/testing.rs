@@ -0,0 +1,79 @@
+use crate::{
+    alert::{Aggregation, AlertTarget, Comparison, CreateRequest},
+    Kodama,
+};
+use kodama_api::{Labels, Timestamp};
+use std::path::PathBuf;
+
+/// A database directory for tests, removed when dropped.
+pub struct TestDatabase {
+    pub path: PathBuf,
+}
+
+impl TestDatabase {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("kodama-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        Self { path }
+    }
+
+    /// A migrated instance with the service `shop/api`.
+    pub fn instance(&self) -> Kodama {
+        let mut kodama = Kodama::instance(self.path.to_str().unwrap().to_string()).unwrap();
+        kodama.migrate().unwrap();
+        if kodama.get_service_id("shop", "api").is_err() {
+            kodama.create_project("shop", "").unwrap();
+            kodama.create_service("shop", "api", "").unwrap();
+        }
+        kodama
+    }
+
+    /// A migrated instance with the alert `alert_name` on `shop/api`, firing
+    /// when the slowest `checkout` record of the last minute exceeds 100us.
+    pub fn with_alert(&self, alert_name: &str) -> Kodama {
+        let kodama = self.instance();
+        kodama
+            .create_alert(&CreateRequest {
+                project_name: "shop".to_string(),
+                service_name: "api".to_string(),
+                alert_name: alert_name.to_string(),
+                target: AlertTarget::Record,
+                target_name: "checkout".to_string(),
+                aggregation: Aggregation::Max,
+                window_us: 60 * 1_000_000,
+                comparison: Comparison::Gt,
+                threshold: 100.0,
+            })
+            .unwrap();
+        kodama
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// The timestamp `seconds` after the unix epoch.
+pub fn at(seconds: u64) -> Timestamp {
+    Timestamp {
+        microseconds: seconds * 1_000_000,
+    }
+}
+
+/// Add a `shop/api` `checkout` record at `seconds` after the unix epoch.
+pub fn add_record_at(kodama: &mut Kodama, seconds: u64, execution_time_us: u64) {
+    kodama
+        .add_record(
+            "shop",
+            "api",
+            "checkout",
+            "/",
+            Some(at(seconds)),
+            execution_time_us,
+            false,
+            &Labels::new(),
+        )
+        .unwrap();
+}
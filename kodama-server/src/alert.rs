@@ -0,0 +1,65 @@
+use crate::Result;
+use kodama_api::Timestamp;
+use kodama_internal::{alert::AlertChange, Kodama};
+use std::time::Duration;
+
+/// Periodically evaluate the alert rules, logging every alert that starts
+/// firing or resolves. Notifications are delivered by the notify task.
+pub fn start_alert_task(database_path: String, interval: Duration) -> Result<()> {
+    tracing::debug!("- initializing alert task (every {:?})", interval);
+
+    std::thread::Builder::new()
+        .name("alert".to_string())
+        .spawn(move || {
+            let mut instance = match Kodama::instance(database_path) {
+                Ok(instance) => instance,
+                Err(err) => {
+                    tracing::error!("alert: unable to open database: {:?}", err);
+                    return;
+                }
+            };
+
+            loop {
+                match Timestamp::now() {
+                    Some(now) => evaluate(&mut instance, &now),
+                    None => tracing::error!("alert: unable to read current time"),
+                }
+                std::thread::sleep(interval);
+            }
+        })?;
+
+    Ok(())
+}
+
+fn evaluate(instance: &mut Kodama, now: &Timestamp) {
+    match instance.evaluate_alerts(now) {
+        Ok(transitions) => {
+            for transition in transitions {
+                let alert = &transition.alert;
+                match transition.change {
+                    AlertChange::Firing => tracing::warn!(
+                        "alert: {}/{} {} firing, {} {} = {} ({} {})",
+                        alert.project_name,
+                        alert.service_name,
+                        alert.alert_name,
+                        alert.target_name,
+                        alert.aggregation,
+                        transition.value,
+                        alert.comparison,
+                        alert.threshold
+                    ),
+                    AlertChange::Resolved => tracing::info!(
+                        "alert: {}/{} {} resolved, {} {} = {}",
+                        alert.project_name,
+                        alert.service_name,
+                        alert.alert_name,
+                        alert.target_name,
+                        alert.aggregation,
+                        transition.value
+                    ),
+                }
+            }
+        }
+        Err(err) => tracing::error!("alert error: {:?}", err),
+    }
+}
@@ -8,4 +8,12 @@ pub enum Error {
     Utf8Error(#[from] std::str::Utf8Error),
     #[error("serde json Error: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
+    #[error("http error: {0}")]
+    Http(Box<dyn std::error::Error + Send + Sync>),
+    #[error("{0} pending migrations, run `kodama-cli db migrate`")]
+    PendingMigrations(usize),
+    #[error("notify error: {0}")]
+    Notify(String),
+    #[error("ingest writer stopped")]
+    IngestWriterStopped,
 }
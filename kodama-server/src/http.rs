@@ -0,0 +1,475 @@
+use crate::{Error, Result};
+use kodama_api::ErrorResponse;
+use kodama_internal::{
+    alert, anomaly, event, log, metric, notifier, project, record, retention, service, Kodama,
+};
+use std::{io::Read, net::SocketAddr};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest request body accepted, larger requests get a 413 response.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// Serve the JSON query and admin API. Every endpoint except `GET
+/// /api/project/list` takes its request type as a JSON body via `POST`.
+/// Creating and routing notifiers is left to the CLI, since a notifier runs
+/// commands and writes files on the server.
+pub fn start_http_server(listen_addr: SocketAddr, database_path: String) -> Result<()> {
+    tracing::debug!("- initializing http server ({})", listen_addr);
+
+    let server = Server::http(listen_addr).map_err(Error::Http)?;
+    serve(server, database_path)
+}
+
+fn serve(server: Server, database_path: String) -> Result<()> {
+    std::thread::Builder::new()
+        .name("http".to_string())
+        .spawn(move || {
+            let mut instance = match Kodama::instance(database_path) {
+                Ok(instance) => instance,
+                Err(err) => {
+                    tracing::error!("http: unable to open database: {:?}", err);
+                    return;
+                }
+            };
+
+            for request in server.incoming_requests() {
+                if let Err(err) = handle_request(&mut instance, request) {
+                    tracing::error!("http error: {:?}", err);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_request(instance: &mut Kodama, mut request: Request) -> Result<()> {
+    tracing::debug!("http: {} {}", request.method(), request.url());
+
+    let mut data = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_SIZE + 1)
+        .read_to_end(&mut data)?;
+    let too_large = data.len() as u64 > MAX_BODY_SIZE;
+    let body = match too_large {
+        true => String::new(),
+        false => std::str::from_utf8(&data)?.to_string(),
+    };
+
+    let method = request.method().clone();
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let result = match (method, path.as_str()) {
+        _ if too_large => Err(HttpError::PayloadTooLarge),
+        (Method::Get, "/api/project/list") => project_list(instance),
+        (Method::Post, "/api/project/create") => project_create(instance, &body),
+        (Method::Post, "/api/service/list") => service_list(instance, &body),
+        (Method::Post, "/api/service/create") => service_create(instance, &body),
+        (Method::Post, "/api/record/list") => record_list(instance, &body),
+        (Method::Post, "/api/record/data") => record_data(instance, &body),
+        (Method::Post, "/api/record/series") => record_series(instance, &body),
+        (Method::Post, "/api/record/anomalies") => record_anomalies(instance, &body),
+        (Method::Post, "/api/metric/list") => metric_list(instance, &body),
+        (Method::Post, "/api/metric/data") => metric_data(instance, &body),
+        (Method::Post, "/api/log/search") => log_search(instance, &body),
+        (Method::Post, "/api/event/list") => event_list(instance, &body),
+        (Method::Post, "/api/event/count") => event_count(instance, &body),
+        (Method::Post, "/api/event/breakdown") => event_breakdown(instance, &body),
+        (Method::Post, "/api/alert/list") => alert_list(instance, &body),
+        (Method::Post, "/api/alert/create") => alert_create(instance, &body),
+        (Method::Post, "/api/alert/history") => alert_history(instance, &body),
+        (Method::Get, "/api/notifier/list") => notifier_list(instance),
+        (Method::Post, "/api/notifier/routes") => notifier_routes(instance, &body),
+        (Method::Post, "/api/retention/list") => retention_list(instance, &body),
+        (Method::Post, "/api/retention/set") => retention_set(instance, &body),
+        _ => Err(HttpError::NotFound),
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err(err) => {
+            let (status, response) = err.response();
+            (status, serde_json::to_string(&response)?)
+        }
+    };
+
+    let header = Header::from_bytes("Content-Type", "application/json").expect("header");
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header);
+    request.respond(response)?;
+    Ok(())
+}
+
+enum HttpError {
+    NotFound,
+    PayloadTooLarge,
+    BadRequest(serde_json::Error),
+    Kodama(kodama_internal::Error),
+}
+
+impl HttpError {
+    fn response(&self) -> (u16, ErrorResponse) {
+        match self {
+            Self::NotFound => (
+                404,
+                ErrorResponse {
+                    code: 404,
+                    message: "endpoint not found".to_string(),
+                },
+            ),
+            Self::PayloadTooLarge => (
+                413,
+                ErrorResponse {
+                    code: 413,
+                    message: format!("request body exceeds {} bytes", MAX_BODY_SIZE),
+                },
+            ),
+            Self::BadRequest(err) => (
+                400,
+                ErrorResponse {
+                    code: 400,
+                    message: format!("invalid request: {}", err),
+                },
+            ),
+            Self::Kodama(kodama_internal::Error::ApiError(err)) => {
+                let status = match err.code() / 10000 {
+                    1 => 400,
+                    2 => 404,
+                    3 => 409,
+                    _ => 500,
+                };
+                (status, err.json())
+            }
+            Self::Kodama(err) => {
+                tracing::error!("{:?}", err);
+                (
+                    500,
+                    ErrorResponse {
+                        code: 500,
+                        message: "internal server error".to_string(),
+                    },
+                )
+            }
+        }
+    }
+}
+
+impl From<serde_json::Error> for HttpError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::BadRequest(err)
+    }
+}
+
+impl From<kodama_internal::Error> for HttpError {
+    fn from(err: kodama_internal::Error) -> Self {
+        Self::Kodama(err)
+    }
+}
+
+type HttpResult = std::result::Result<String, HttpError>;
+
+fn json(value: &impl serde::Serialize) -> HttpResult {
+    Ok(serde_json::to_string(value).expect("serde_json::to_string"))
+}
+
+fn project_list(instance: &mut Kodama) -> HttpResult {
+    let projects = instance.project_list()?;
+    json(&project::ListResponse { projects })
+}
+
+fn project_create(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: project::CreateRequest = serde_json::from_str(body)?;
+    let project_id =
+        instance.create_project(&request.project_name, &request.project_description)?;
+    json(&project::CreateResponse { project_id })
+}
+
+fn service_list(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: service::ListRequest = serde_json::from_str(body)?;
+    let services = instance.service_list(&request.project_name)?;
+    json(&service::ListResponse { services })
+}
+
+fn service_create(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: service::CreateRequest = serde_json::from_str(body)?;
+    let service_id = instance.create_service(
+        &request.project_name,
+        &request.service_name,
+        &request.service_description,
+    )?;
+    json(&service::CreateResponse { service_id })
+}
+
+fn record_list(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: record::ListRequest = serde_json::from_str(body)?;
+    let records = instance.record_list(&request.project_name, &request.service_name)?;
+    json(&record::ListResponse { records })
+}
+
+fn record_data(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: record::DataRequest = serde_json::from_str(body)?;
+    let entries = instance.record_entries(
+        &request.project_name,
+        &request.service_name,
+        &request.record_name,
+        request.from.as_ref(),
+        request.to.as_ref(),
+        &request.percentiles,
+        &request.labels,
+        request.group_by_label.as_deref(),
+    )?;
+    json(&record::DataResponse { entries })
+}
+
+fn record_series(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: record::SeriesRequest = serde_json::from_str(body)?;
+    let entries = instance.record_series(
+        &request.project_name,
+        &request.service_name,
+        &request.record_name,
+        request.bucket,
+        request.group_by.as_deref(),
+        request.from.as_ref(),
+        request.to.as_ref(),
+        &request.labels,
+    )?;
+    json(&record::SeriesResponse { entries })
+}
+
+fn record_anomalies(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: anomaly::AnomalyRequest = serde_json::from_str(body)?;
+    let anomalies = instance.record_anomalies(
+        &request.project_name,
+        &request.service_name,
+        &request.record_name,
+        request.to.as_ref(),
+        request.window_us,
+        request.baseline,
+        request
+            .windows
+            .unwrap_or_else(|| request.baseline.default_windows()),
+        request.sensitivity.unwrap_or(anomaly::DEFAULT_SENSITIVITY),
+    )?;
+    json(&anomaly::AnomalyResponse { anomalies })
+}
+
+fn metric_list(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: metric::ListRequest = serde_json::from_str(body)?;
+    let metrics = instance.metric_list(&request.project_name, &request.service_name)?;
+    json(&metric::ListResponse { metrics })
+}
+
+fn metric_data(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: metric::DataRequest = serde_json::from_str(body)?;
+    let values = instance.metric_values(
+        &request.project_name,
+        &request.service_name,
+        &request.metric_name,
+        &request.labels,
+    )?;
+    json(&metric::DataResponse { values })
+}
+
+fn log_search(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: log::SearchRequest = serde_json::from_str(body)?;
+    let entries = instance.log_search(
+        &request.project_name,
+        &request.service_name,
+        request.level,
+        request.from.as_ref(),
+        request.to.as_ref(),
+        request.text.as_deref(),
+        request.limit.unwrap_or(log::DEFAULT_LIMIT),
+    )?;
+    json(&log::SearchResponse { entries })
+}
+
+fn event_list(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: event::ListRequest = serde_json::from_str(body)?;
+    let events = instance.event_list(&request.project_name, &request.service_name)?;
+    json(&event::ListResponse { events })
+}
+
+fn event_count(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: event::CountRequest = serde_json::from_str(body)?;
+    let entries = instance.event_counts(
+        &request.project_name,
+        &request.service_name,
+        &request.event_name,
+        request.bucket,
+        request.from.as_ref(),
+        request.to.as_ref(),
+    )?;
+    json(&event::CountResponse { entries })
+}
+
+fn event_breakdown(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: event::BreakdownRequest = serde_json::from_str(body)?;
+    let entries = instance.event_breakdown(
+        &request.project_name,
+        &request.service_name,
+        &request.event_name,
+        &request.field,
+        request.from.as_ref(),
+        request.to.as_ref(),
+    )?;
+    json(&event::BreakdownResponse { entries })
+}
+
+fn alert_list(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: alert::ListRequest = serde_json::from_str(body)?;
+    let alerts = instance.alert_list(request.project_name.as_deref())?;
+    json(&alert::ListResponse { alerts })
+}
+
+fn alert_create(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: alert::CreateRequest = serde_json::from_str(body)?;
+    let alert_id = instance.create_alert(&request)?;
+    json(&alert::CreateResponse { alert_id })
+}
+
+fn alert_history(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: alert::HistoryRequest = serde_json::from_str(body)?;
+    let entries = instance.alert_history(
+        &request.project_name,
+        request.service_name.as_deref(),
+        request.alert_name.as_deref(),
+        request.limit.unwrap_or(alert::DEFAULT_HISTORY_LIMIT),
+    )?;
+    json(&alert::HistoryResponse { entries })
+}
+
+fn notifier_list(instance: &mut Kodama) -> HttpResult {
+    let notifiers = instance.notifier_list()?;
+    json(&notifier::ListResponse { notifiers })
+}
+
+fn notifier_routes(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: notifier::RouteListRequest = serde_json::from_str(body)?;
+    let routes = instance.route_list(request.project_name.as_deref())?;
+    json(&notifier::RouteListResponse { routes })
+}
+
+fn retention_list(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: retention::ListRequest = serde_json::from_str(body)?;
+    let policies = instance.retention_list(request.project_name.as_deref())?;
+    json(&retention::ListResponse { policies })
+}
+
+fn retention_set(instance: &mut Kodama, body: &str) -> HttpResult {
+    let request: retention::SetRequest = serde_json::from_str(body)?;
+    instance.set_retention(
+        &request.project_name,
+        request.service_name.as_deref(),
+        request.record_name.as_deref(),
+        request.keep_us,
+    )?;
+    json(&retention::SetResponse {})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kodama_api::Labels;
+    use std::{io::Write, net::TcpStream};
+
+    fn send(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    /// The status and `ErrorResponse` code of a failing request.
+    fn error(addr: SocketAddr, path: &str, body: &str) -> (u16, u16) {
+        let (status, body) = send(addr, "POST", path, body);
+        let response: ErrorResponse = serde_json::from_str(&body).unwrap();
+        (status, response.code)
+    }
+
+    #[test]
+    fn status_codes() {
+        let path = std::env::temp_dir().join(format!("kodama-http-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let database_path = path.to_str().unwrap().to_string();
+        let mut kodama = Kodama::instance(database_path.clone()).unwrap();
+        kodama.migrate().unwrap();
+        kodama.create_project("shop", "").unwrap();
+        kodama.create_service("shop", "api", "").unwrap();
+        kodama
+            .add_record(
+                "shop",
+                "api",
+                "checkout",
+                "/",
+                None,
+                10,
+                false,
+                &Labels::new(),
+            )
+            .unwrap();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        serve(server, database_path).unwrap();
+
+        let (status, body) = send(addr, "GET", "/api/project/list", "");
+        assert_eq!(status, 200);
+        let list: project::ListResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(list.projects[0].name, "shop");
+
+        let create = |project_name: &str| {
+            serde_json::to_string(&project::CreateRequest {
+                project_name: project_name.to_string(),
+                project_description: "".to_string(),
+            })
+            .unwrap()
+        };
+        let (status, body) = send(addr, "POST", "/api/project/create", &create("blog"));
+        assert_eq!(status, 200);
+        let created: project::CreateResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(created.project_id, 2);
+
+        // api errors are mapped by the leading digit of their code
+        assert_eq!(
+            error(addr, "/api/project/create", &create("shop")),
+            (409, 30001)
+        );
+        assert_eq!(
+            error(addr, "/api/service/list", r#"{"project_name": "docs"}"#),
+            (404, 20001)
+        );
+        assert_eq!(
+            error(
+                addr,
+                "/api/record/data",
+                r#"{"project_name": "shop", "service_name": "api", "record_name": "checkout",
+                "percentiles": [150]}"#
+            ),
+            (400, 10004)
+        );
+
+        assert_eq!(error(addr, "/api/service/list", "{"), (400, 400));
+        assert_eq!(error(addr, "/api/notifier/create", "{}"), (404, 404));
+        let large = " ".repeat(MAX_BODY_SIZE as usize + 1);
+        assert_eq!(error(addr, "/api/service/list", &large), (413, 413));
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{handle_command, Error, Result};
+use kodama_api::{Command, MAX_DATAGRAM_SIZE};
+use kodama_internal::{AutoProvision, Kodama, WriteBatching};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Settings for the UDP ingest pipeline.
+#[derive(Debug, Clone)]
+pub struct IngestConfig {
+    /// Number of writer threads, each owning its own database connections.
+    pub workers: usize,
+    /// Number of parsed datagrams each writer can have queued before new
+    /// ones are dropped.
+    pub queue_size: usize,
+    /// How often the counters are logged.
+    pub stats_interval: Duration,
+    /// Batch inserts per service, disabled with a flush interval of zero.
+    pub write_batching: Option<WriteBatching>,
+}
+
+impl IngestConfig {
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name)
+                .ok()
+                .map(|value| value.parse().unwrap_or_else(|_| panic!("{}", name)))
+        }
+
+        let workers = var("KODAMA_INGEST_WORKERS").unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|workers| workers.get().min(4))
+                .unwrap_or(1)
+        });
+
+        let default_batching = WriteBatching::default();
+        let flush_interval = var("KODAMA_FLUSH_INTERVAL_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(default_batching.flush_interval);
+        let write_batching = (!flush_interval.is_zero()).then(|| WriteBatching {
+            max_rows: var("KODAMA_FLUSH_MAX_ROWS")
+                .unwrap_or(default_batching.max_rows)
+                .max(1),
+            flush_interval,
+        });
+
+        Self {
+            workers: workers.max(1),
+            queue_size: var("KODAMA_INGEST_QUEUE_SIZE").unwrap_or(1024).max(1),
+            stats_interval: Duration::from_secs(var("KODAMA_INGEST_STATS_INTERVAL").unwrap_or(60)),
+            write_batching,
+        }
+    }
+}
+
+/// Counters shared by the receive thread and the writers. Everything is
+/// counted per datagram.
+#[derive(Debug, Default)]
+pub struct IngestStats {
+    pub received: AtomicU64,
+    pub oversized: AtomicU64,
+    pub invalid: AtomicU64,
+    pub dropped: AtomicU64,
+    /// Datagrams currently waiting for a writer.
+    pub queued: AtomicU64,
+    pub processed: AtomicU64,
+    pub failed: AtomicU64,
+}
+
+impl IngestStats {
+    fn log(&self) {
+        tracing::info!(
+            "ingest: received={} oversized={} invalid={} dropped={} queued={} processed={} failed={}",
+            self.received.load(Ordering::Relaxed),
+            self.oversized.load(Ordering::Relaxed),
+            self.invalid.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.queued.load(Ordering::Relaxed),
+            self.processed.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Receive datagrams on the calling thread and hand them to a pool of
+/// writer threads through bounded queues, so slow inserts never hold up
+/// `recv_from`. Datagrams are routed by service, so the UDP datagrams of a
+/// service are written in the order they were received. Other servers, such
+/// as the TCP ingest, may still write to the same service database.
+pub fn start_data_server(
+    listen_addr: SocketAddr,
+    database_path: String,
+    auto_provision: Option<AutoProvision>,
+    config: IngestConfig,
+) -> Result<()> {
+    tracing::debug!("- initializing data server ({}, {:?})", listen_addr, config);
+    if let Some(auto_provision) = &auto_provision {
+        tracing::debug!("- auto provisioning enabled ({:?})", auto_provision);
+    }
+
+    let stats = Arc::new(IngestStats::default());
+    let mut writers = Vec::with_capacity(config.workers);
+    for index in 0..config.workers {
+        // `Kodama` is not `Send`, so each writer opens its own instance
+        let (sender, receiver) = sync_channel(config.queue_size);
+        let database_path = database_path.clone();
+        let auto_provision = auto_provision.clone();
+        let write_batching = config.write_batching;
+        let stats = stats.clone();
+        std::thread::Builder::new()
+            .name(format!("ingest-writer-{}", index))
+            .spawn(move || {
+                let result = run_writer(
+                    receiver,
+                    database_path,
+                    auto_provision,
+                    write_batching,
+                    &stats,
+                );
+                if let Err(err) = result {
+                    tracing::error!("ingest writer {} stopped: {:?}", index, err);
+                }
+            })?;
+        writers.push(sender);
+    }
+
+    if !config.stats_interval.is_zero() {
+        let stats = stats.clone();
+        std::thread::Builder::new()
+            .name("ingest-stats".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(config.stats_interval);
+                stats.log();
+            })?;
+    }
+
+    let socket = UdpSocket::bind(listen_addr)?;
+    receive(&socket, &writers, &stats)
+}
+
+fn receive(socket: &UdpSocket, writers: &[SyncSender<Command>], stats: &IngestStats) -> Result<()> {
+    // one byte larger than any datagram we accept, so a payload filling the
+    // whole buffer was either oversized or truncated by the kernel
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE + 1];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf)?;
+        tracing::debug!("[{}] {} bytes", addr, len);
+        stats.received.fetch_add(1, Ordering::Relaxed);
+
+        if len > MAX_DATAGRAM_SIZE {
+            let oversized = stats.oversized.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!(
+                "[{}] dropping oversized datagram ({} bytes or more, limit {} bytes, {} dropped in total)",
+                addr,
+                len,
+                MAX_DATAGRAM_SIZE,
+                oversized
+            );
+            continue;
+        }
+
+        let command = match parse_datagram(&buf[..len]) {
+            Ok(command) => command,
+            Err(err) => {
+                stats.invalid.fetch_add(1, Ordering::Relaxed);
+                tracing::error!("[{}] invalid datagram: {:?}", addr, err);
+                continue;
+            }
+        };
+
+        let writer = &writers[writer_index(&command, writers.len())];
+        stats.queued.fetch_add(1, Ordering::Relaxed);
+        match writer.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                stats.queued.fetch_sub(1, Ordering::Relaxed);
+                let dropped = stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                tracing::warn!(
+                    "[{}] ingest queue full, dropping datagram ({} dropped in total)",
+                    addr,
+                    dropped
+                );
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(Error::IngestWriterStopped);
+            }
+        }
+    }
+}
+
+fn parse_datagram(buf: &[u8]) -> Result<Command> {
+    let utf8_data = std::str::from_utf8(buf)?;
+    Ok(serde_json::from_str::<Command>(utf8_data)?)
+}
+
+/// Pick the writer for a datagram from the service of its first command. A
+/// batch mixing services is written entirely by the writer of its first
+/// command, the batches sent by `kodama_api::Client` contain a single
+/// service.
+fn writer_index(command: &Command, writers: usize) -> usize {
+    let mut command = command;
+    while let Command::Batch(commands) = command {
+        match commands.first() {
+            Some(first) => command = first,
+            None => return 0,
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    match command {
+        Command::Record(record) => (&record.project_name, &record.service_name).hash(&mut hasher),
+        Command::Metric(metric) => (&metric.project_name, &metric.service_name).hash(&mut hasher),
+        Command::Log(log) => (&log.project_name, &log.service_name).hash(&mut hasher),
+        Command::Event(event) => (&event.project_name, &event.service_name).hash(&mut hasher),
+        Command::Batch(_) => unreachable!("batches are unwrapped above"),
+    }
+    (hasher.finish() % writers as u64) as usize
+}
+
+fn run_writer(
+    receiver: Receiver<Command>,
+    database_path: String,
+    auto_provision: Option<AutoProvision>,
+    write_batching: Option<WriteBatching>,
+    stats: &IngestStats,
+) -> Result<()> {
+    let mut instance = Kodama::instance(database_path)?;
+    if let Some(auto_provision) = auto_provision {
+        instance = instance.with_auto_provision(auto_provision);
+    }
+    if let Some(write_batching) = write_batching {
+        instance = instance.with_write_batching(write_batching);
+    }
+
+    loop {
+        let command = match instance.flush_deadline() {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match receiver.recv_timeout(timeout) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match receiver.recv() {
+                Ok(command) => Some(command),
+                Err(_) => break,
+            },
+        };
+
+        if let Some(command) = command {
+            stats.queued.fetch_sub(1, Ordering::Relaxed);
+            stats.processed.fetch_add(1, Ordering::Relaxed);
+            if let Err(err) = handle_command(&mut instance, command) {
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                tracing::error!("error: {:?}", err);
+            }
+        }
+
+        if let Err(err) = instance.flush_expired() {
+            tracing::error!("unable to flush write batches: {:?}", err);
+        }
+    }
+
+    instance.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kodama_api::{Metric, Record};
+
+    #[test]
+    fn writer_routing() {
+        let record = |service: &str| {
+            Command::Record(Record {
+                project_name: "project".to_string(),
+                service_name: service.to_string(),
+                record_name: "record".to_string(),
+                group_by: "".to_string(),
+                timestamp: None,
+                execution_time_us: 1,
+                error: 0,
+                labels: Default::default(),
+            })
+        };
+        let metric = |service: &str| {
+            Command::Metric(Metric {
+                project_name: "project".to_string(),
+                service_name: service.to_string(),
+                metric_name: "metric".to_string(),
+                metric_timestamp: None,
+                metric_value: 1.0,
+                labels: Default::default(),
+            })
+        };
+
+        for service in ["a", "b", "c", "d"] {
+            let index = writer_index(&record(service), 7);
+            assert!(index < 7);
+            assert_eq!(writer_index(&metric(service), 7), index);
+            assert_eq!(
+                writer_index(
+                    &Command::Batch(vec![Command::Batch(vec![record(service)])]),
+                    7
+                ),
+                index
+            );
+        }
+        assert_eq!(writer_index(&Command::Batch(vec![]), 7), 0);
+    }
+}
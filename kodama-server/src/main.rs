@@ -1,8 +1,15 @@
 use kodama_api::Command;
-use kodama_internal::Kodama;
+use kodama_internal::{AutoProvision, Kodama};
 use std::net::SocketAddr;
 
+mod alert;
 mod error;
+mod http;
+mod ingest;
+mod notify;
+mod retention;
+mod rollup;
+mod tcp;
 
 pub type Result<T> = std::result::Result<T, Error>;
 pub use error::Error;
@@ -25,57 +32,237 @@ fn main() -> Result<()> {
         .parse::<SocketAddr>()
         .expect("KODAMA_LISTEN_ADDR");
 
-    tracing::debug!("- initializing database");
-    Kodama::instance(database_path.clone())?.initialize()?;
+    let auto_migrate = std::env::var("KODAMA_AUTO_MIGRATE")
+        .map(|value| value != "0" && value != "false")
+        .unwrap_or(true);
+
+    tracing::debug!("- checking database migrations");
+    let mut instance = Kodama::instance(database_path.clone())?;
+    let pending = instance
+        .migration_status()?
+        .into_iter()
+        .filter(|migration| !migration.applied)
+        .collect::<Vec<_>>();
+    for migration in &pending {
+        tracing::info!(
+            "pending migration: {} {}",
+            migration.database,
+            migration.version
+        );
+    }
+
+    if !pending.is_empty() {
+        if !auto_migrate {
+            return Err(Error::PendingMigrations(pending.len()));
+        }
+
+        tracing::debug!("- migrating database");
+        instance.migrate()?;
+    }
+
+    // held until the server exits, see `Kodama::server_lock`
+    let _server_lock = instance.server_lock()?;
+
+    let auto_provision = std::env::var("KODAMA_AUTO_PROVISION")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false)
+        .then(|| {
+            let patterns = std::env::var("KODAMA_AUTO_PROVISION_PATTERNS").unwrap_or_default();
+            let patterns = patterns
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .collect::<Vec<_>>();
+            if patterns.is_empty() {
+                tracing::warn!(
+                    "auto provisioning is enabled without KODAMA_AUTO_PROVISION_PATTERNS, \
+                    use `*` to provision every service"
+                );
+            }
+            AutoProvision::with_patterns(patterns)
+        });
+
+    if let Ok(tcp_listen_addr) = std::env::var("KODAMA_TCP_LISTEN_ADDR") {
+        let tcp_listen_addr = tcp_listen_addr
+            .parse::<SocketAddr>()
+            .expect("KODAMA_TCP_LISTEN_ADDR");
+        let tcp_max_connections = std::env::var("KODAMA_TCP_MAX_CONNECTIONS")
+            .map(|value| value.parse::<usize>().expect("KODAMA_TCP_MAX_CONNECTIONS"))
+            .unwrap_or(64);
+        tcp::start_tcp_server(
+            tcp_listen_addr,
+            database_path.clone(),
+            auto_provision.clone(),
+            tcp_max_connections,
+        )?;
+    }
+
+    if let Ok(http_listen_addr) = std::env::var("KODAMA_HTTP_LISTEN_ADDR") {
+        let http_listen_addr = http_listen_addr
+            .parse::<SocketAddr>()
+            .expect("KODAMA_HTTP_LISTEN_ADDR");
+        http::start_http_server(http_listen_addr, database_path.clone())?;
+    }
+
+    let retention_interval = std::env::var("KODAMA_RETENTION_INTERVAL")
+        .map(|value| value.parse::<u64>().expect("KODAMA_RETENTION_INTERVAL"))
+        .unwrap_or(60 * 60);
+    if retention_interval > 0 {
+        retention::start_retention_task(
+            database_path.clone(),
+            std::time::Duration::from_secs(retention_interval),
+        )?;
+    }
+
+    let rollup_interval = std::env::var("KODAMA_ROLLUP_INTERVAL")
+        .map(|value| value.parse::<u64>().expect("KODAMA_ROLLUP_INTERVAL"))
+        .unwrap_or(60);
+    if rollup_interval > 0 {
+        rollup::start_rollup_task(
+            database_path.clone(),
+            std::time::Duration::from_secs(rollup_interval),
+        )?;
+    }
+
+    let alert_interval = std::env::var("KODAMA_ALERT_INTERVAL")
+        .map(|value| value.parse::<u64>().expect("KODAMA_ALERT_INTERVAL"))
+        .unwrap_or(60);
+    if alert_interval > 0 {
+        alert::start_alert_task(
+            database_path.clone(),
+            std::time::Duration::from_secs(alert_interval),
+        )?;
+        notify::start_notify_task(
+            database_path.clone(),
+            std::time::Duration::from_secs(alert_interval),
+        )?;
+    }
 
     let server_database_path = database_path.clone();
-    start_data_server(listen_addr, server_database_path)?;
+    ingest::start_data_server(
+        listen_addr,
+        server_database_path,
+        auto_provision,
+        ingest::IngestConfig::from_env(),
+    )?;
 
     Ok(())
 }
 
-fn handle_request(instance: &mut Kodama, buf: &[u8]) -> Result<()> {
-    let utf8_data = std::str::from_utf8(buf)?;
-    let data: Command = serde_json::from_str::<Command>(utf8_data)?;
+/// Insert a batch with one transaction per service, keeping the order of
+/// commands within each service. A failing command rolls back all commands
+/// of its service.
+fn handle_batch(instance: &mut Kodama, commands: Vec<Command>) -> Result<()> {
+    let mut flattened = Vec::with_capacity(commands.len());
+    flatten_batch(commands, &mut flattened);
 
-    match data {
-        Command::Record(record) => {
-            if record.error > 0 {
+    let mut services: Vec<((String, String), Vec<Command>)> = Vec::new();
+    for command in flattened {
+        let key = match &command {
+            Command::Record(record) => (record.project_name.clone(), record.service_name.clone()),
+            Command::Metric(metric) => (metric.project_name.clone(), metric.service_name.clone()),
+            Command::Log(log) => (log.project_name.clone(), log.service_name.clone()),
+            Command::Event(event) => (event.project_name.clone(), event.service_name.clone()),
+            Command::Batch(_) => unreachable!("nested batches are flattened"),
+        };
+
+        match services.iter_mut().find(|(service, _)| *service == key) {
+            Some((_, commands)) => commands.push(command),
+            None => services.push((key, vec![command])),
+        }
+    }
+
+    let mut result = Ok(());
+    for ((project_name, service_name), commands) in services {
+        tracing::debug!(
+            "batch: {} commands for {}/{}",
+            commands.len(),
+            project_name,
+            service_name
+        );
+
+        let transaction = instance.service_transaction(&project_name, &service_name, |instance| {
+            for command in commands {
+                insert_command(instance, command)?;
+            }
+            Ok(())
+        });
+
+        // report the first failed service to the caller and log the rest
+        if let Err(err) = transaction {
+            if result.is_ok() {
+                result = Err(err.into());
             } else {
-                instance.add_record(
-                    &record.project_name,
-                    &record.service_name,
-                    &record.record_name,
-                    &record.group_by,
-                    record.timestamp,
-                    record.execution_time_us,
-                )?;
+                tracing::error!("error: {:?}", err);
             }
         }
-        _ => {
-            tracing::error!("unknown command: {:?}", data);
-        }
     }
 
-    Ok(())
+    result
 }
 
-fn start_data_server(listen_addr: SocketAddr, database_path: String) -> Result<()> {
-    tracing::debug!("- initializing data server ({})", listen_addr);
-
-    let socket = std::net::UdpSocket::bind(listen_addr)?;
-    let mut instance = Kodama::instance(database_path)?;
+fn flatten_batch(commands: Vec<Command>, output: &mut Vec<Command>) {
+    for command in commands {
+        match command {
+            Command::Batch(commands) => flatten_batch(commands, output),
+            command => output.push(command),
+        }
+    }
+}
 
-    let mut buf = [0; 1024];
-    loop {
-        let (len, addr) = socket.recv_from(&mut buf)?;
-        tracing::debug!("[{}] {} bytes", addr, len);
+fn handle_command(instance: &mut Kodama, command: Command) -> Result<()> {
+    match command {
+        Command::Batch(commands) => handle_batch(instance, commands),
+        command => Ok(insert_command(instance, command)?),
+    }
+}
 
-        match handle_request(&mut instance, &buf[..len]) {
-            Ok(_) => {}
-            Err(err) => {
-                tracing::error!("error: {:?}", err);
-            }
+fn insert_command(instance: &mut Kodama, command: Command) -> kodama_internal::Result<()> {
+    match command {
+        Command::Record(record) => {
+            instance.add_record(
+                &record.project_name,
+                &record.service_name,
+                &record.record_name,
+                &record.group_by,
+                record.timestamp,
+                record.execution_time_us,
+                record.error > 0,
+                &record.labels,
+            )?;
         }
+        Command::Metric(metric) => {
+            instance.add_metric(
+                &metric.project_name,
+                &metric.service_name,
+                &metric.metric_name,
+                metric.metric_timestamp,
+                metric.metric_value,
+                &metric.labels,
+            )?;
+        }
+        Command::Log(log) => {
+            instance.add_log(
+                &log.project_name,
+                &log.service_name,
+                log.timestamp,
+                log.level,
+                &log.target,
+                &log.message,
+                &log.fields,
+            )?;
+        }
+        Command::Event(event) => {
+            instance.add_event(
+                &event.project_name,
+                &event.service_name,
+                &event.event_name,
+                event.timestamp,
+                &event.payload,
+            )?;
+        }
+        Command::Batch(_) => unreachable!("batches are split up by handle_command"),
     }
+
+    Ok(())
 }
@@ -0,0 +1,272 @@
+use crate::{Error, Result};
+use kodama_api::Timestamp;
+use kodama_internal::{
+    notifier::{AlertMessage, Notification, NotifierKind},
+    Kodama,
+};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+/// Time allowed for a single delivery.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Periodically deliver the notifications that are due. Deliveries run on
+/// their own thread, so a slow notifier does not hold up alert evaluation.
+pub fn start_notify_task(database_path: String, interval: Duration) -> Result<()> {
+    tracing::debug!("- initializing notify task (every {:?})", interval);
+
+    std::thread::Builder::new()
+        .name("notify".to_string())
+        .spawn(move || {
+            let instance = match Kodama::instance(database_path) {
+                Ok(instance) => instance,
+                Err(err) => {
+                    tracing::error!("notify: unable to open database: {:?}", err);
+                    return;
+                }
+            };
+
+            loop {
+                match Timestamp::now() {
+                    Some(now) => {
+                        if let Err(err) = deliver_notifications(&instance, &now) {
+                            tracing::error!("notify error: {:?}", err);
+                        }
+                    }
+                    None => tracing::error!("notify: unable to read current time"),
+                }
+                std::thread::sleep(interval);
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Send every due notification, marking the delivered ones. Failed
+/// deliveries are retried on the next call.
+pub fn deliver_notifications(instance: &Kodama, now: &Timestamp) -> Result<()> {
+    for notification in instance.due_notifications(now)? {
+        let message = &notification.message;
+        match send(&notification) {
+            Ok(()) => {
+                tracing::debug!(
+                    "notify: {}/{} {} {} sent to {}",
+                    message.project_name,
+                    message.service_name,
+                    message.alert_name,
+                    message.change,
+                    notification.notifier.name
+                );
+                instance.mark_notified(&notification, now)?;
+            }
+            Err(err) => tracing::error!(
+                "notify: unable to send {} {} to {}: {}",
+                message.alert_name,
+                message.change,
+                notification.notifier.name,
+                err
+            ),
+        }
+    }
+    Ok(())
+}
+
+pub fn send(notification: &Notification) -> Result<()> {
+    let target = &notification.notifier.target;
+    match notification.notifier.kind {
+        NotifierKind::Webhook => post_webhook(target, &serde_json::to_vec(&notification.message)?),
+        NotifierKind::Command => run_command(target, &notification.message),
+        NotifierKind::File => append_file(target, &notification.message),
+    }
+}
+
+/// POST `body` as JSON over plain HTTP/1.1, expecting a 2xx response.
+fn post_webhook(url: &str, body: &[u8]) -> Result<()> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| Error::Notify(format!("unsupported url: {}", url)))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let addr = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::Notify(format!("unable to resolve {}", host)))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: kodama/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(Error::Notify(format!(
+            "unexpected response: {}",
+            status_line.trim()
+        ))),
+    }
+}
+
+/// Run `command` with `sh -c`, killing it after the timeout.
+fn run_command(command: &str, message: &AlertMessage) -> Result<()> {
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(message_env(message)?)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return match status.success() {
+                true => Ok(()),
+                false => Err(Error::Notify(format!("command failed: {}", status))),
+            };
+        }
+        if started.elapsed() > TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            return Err(Error::Notify("command timed out".to_string()));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn message_env(message: &AlertMessage) -> Result<Vec<(&'static str, String)>> {
+    Ok(vec![
+        ("KODAMA_ALERT_PROJECT", message.project_name.clone()),
+        ("KODAMA_ALERT_SERVICE", message.service_name.clone()),
+        ("KODAMA_ALERT_NAME", message.alert_name.clone()),
+        ("KODAMA_ALERT_CHANGE", message.change.to_string()),
+        ("KODAMA_ALERT_REPEAT", message.repeat.to_string()),
+        ("KODAMA_ALERT_VALUE", message.value.to_string()),
+        ("KODAMA_ALERT_TARGET", message.target.to_string()),
+        ("KODAMA_ALERT_TARGET_NAME", message.target_name.clone()),
+        ("KODAMA_ALERT_AGGREGATION", message.aggregation.to_string()),
+        ("KODAMA_ALERT_COMPARISON", message.comparison.to_string()),
+        ("KODAMA_ALERT_THRESHOLD", message.threshold.to_string()),
+        (
+            "KODAMA_ALERT_TIMESTAMP",
+            message.timestamp.microseconds.to_string(),
+        ),
+        ("KODAMA_ALERT_JSON", serde_json::to_string(message)?),
+    ])
+}
+
+fn append_file(path: &str, message: &AlertMessage) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kodama_internal::alert::{Aggregation, AlertChange, AlertTarget, Comparison};
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn message() -> AlertMessage {
+        AlertMessage {
+            project_name: "shop".to_string(),
+            service_name: "api".to_string(),
+            alert_name: "slow".to_string(),
+            change: AlertChange::Firing,
+            repeat: false,
+            value: 250.0,
+            target: AlertTarget::Record,
+            target_name: "checkout".to_string(),
+            aggregation: Aggregation::P99,
+            window_us: 60_000_000,
+            comparison: Comparison::Gt,
+            threshold: 100.0,
+            timestamp: Timestamp { microseconds: 42 },
+        }
+    }
+
+    #[test]
+    fn webhook_command_and_file() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/kodama", listener.local_addr().unwrap());
+        let stub = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in ["200 OK", "500 Internal Server Error"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                let mut buffer = [0; 4096];
+                while !request
+                    .split_once("\r\n\r\n")
+                    .is_some_and(|(headers, body)| {
+                        headers.lines().any(|line| {
+                            line.strip_prefix("Content-Length: ") == Some(&body.len().to_string())
+                        })
+                    })
+                {
+                    let read = stream.read(&mut buffer).unwrap();
+                    request.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+                }
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let body = serde_json::to_vec(&message()).unwrap();
+        post_webhook(&url, &body).unwrap();
+        assert!(post_webhook(&url, &body).is_err());
+        let requests = stub.join().unwrap();
+        assert!(requests[0].starts_with("POST /hooks/kodama HTTP/1.1\r\n"));
+        let (_, json) = requests[0].split_once("\r\n\r\n").unwrap();
+        let sent: AlertMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(sent.alert_name, "slow");
+        assert_eq!(sent.change, AlertChange::Firing);
+
+        let path = std::env::temp_dir().join(format!("kodama-notify-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        run_command(
+            &format!(
+                "printf '%s %s\\n' \"$KODAMA_ALERT_NAME\" \"$KODAMA_ALERT_CHANGE\" >> {}",
+                path
+            ),
+            &message(),
+        )
+        .unwrap();
+        append_file(path, &message()).unwrap();
+        assert!(run_command("exit 3", &message()).is_err());
+
+        let contents = std::fs::read_to_string(path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "slow firing");
+        let appended: AlertMessage = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(appended.value, 250.0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
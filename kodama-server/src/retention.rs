@@ -0,0 +1,35 @@
+use crate::Result;
+use kodama_api::Timestamp;
+use kodama_internal::Kodama;
+use std::time::Duration;
+
+/// Periodically delete samples older than their retention policy allows.
+pub fn start_retention_task(database_path: String, interval: Duration) -> Result<()> {
+    tracing::debug!("- initializing retention task (every {:?})", interval);
+
+    std::thread::Builder::new()
+        .name("retention".to_string())
+        .spawn(move || {
+            let instance = match Kodama::instance(database_path) {
+                Ok(instance) => instance,
+                Err(err) => {
+                    tracing::error!("retention: unable to open database: {:?}", err);
+                    return;
+                }
+            };
+
+            loop {
+                match Timestamp::now() {
+                    Some(now) => match instance.prune(&now) {
+                        Ok(0) => {}
+                        Ok(deleted) => tracing::info!("retention: pruned {} rows", deleted),
+                        Err(err) => tracing::error!("retention error: {:?}", err),
+                    },
+                    None => tracing::error!("retention: unable to read current time"),
+                }
+                std::thread::sleep(interval);
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use crate::Result;
+use kodama_api::Timestamp;
+use kodama_internal::Kodama;
+use std::time::Duration;
+
+/// Periodically roll up complete minutes of record data into minute, hour
+/// and day rollups, which queries use once the raw records are pruned.
+pub fn start_rollup_task(database_path: String, interval: Duration) -> Result<()> {
+    tracing::debug!("- initializing rollup task (every {:?})", interval);
+
+    std::thread::Builder::new()
+        .name("rollup".to_string())
+        .spawn(move || {
+            let instance = match Kodama::instance(database_path) {
+                Ok(instance) => instance,
+                Err(err) => {
+                    tracing::error!("rollup: unable to open database: {:?}", err);
+                    return;
+                }
+            };
+
+            loop {
+                match Timestamp::now() {
+                    Some(now) => match instance.rollup(&now) {
+                        Ok(0) => {}
+                        Ok(written) => tracing::debug!("rollup: wrote {} rollups", written),
+                        Err(err) => tracing::error!("rollup error: {:?}", err),
+                    },
+                    None => tracing::error!("rollup: unable to read current time"),
+                }
+                std::thread::sleep(interval);
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use crate::{handle_command, Result};
+use kodama_api::{Ack, StreamMessage};
+use kodama_internal::{AutoProvision, Kodama};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Largest newline-delimited message accepted on a connection.
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Accept newline-delimited `StreamMessage`s over persistent TCP
+/// connections, each connection being served by its own thread. Connections
+/// beyond `max_connections` are closed right away.
+pub fn start_tcp_server(
+    listen_addr: SocketAddr,
+    database_path: String,
+    auto_provision: Option<AutoProvision>,
+    max_connections: usize,
+) -> Result<()> {
+    tracing::debug!(
+        "- initializing tcp server ({}, {} connections)",
+        listen_addr,
+        max_connections
+    );
+
+    let listener = TcpListener::bind(listen_addr)?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    std::thread::Builder::new()
+        .name("tcp-listener".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        tracing::error!("tcp accept error: {:?}", err);
+                        continue;
+                    }
+                };
+
+                if connections.fetch_add(1, Ordering::Relaxed) >= max_connections {
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        "[{:?}] too many connections, closing",
+                        stream.peer_addr().ok()
+                    );
+                    continue;
+                }
+
+                let database_path = database_path.clone();
+                let auto_provision = auto_provision.clone();
+                let thread_connections = connections.clone();
+                let result = std::thread::Builder::new()
+                    .name("tcp-connection".to_string())
+                    .spawn(move || {
+                        let addr = stream.peer_addr().ok();
+                        tracing::debug!("[{:?}] connected", addr);
+                        if let Err(err) = handle_connection(stream, database_path, auto_provision) {
+                            tracing::error!("[{:?}] error: {:?}", addr, err);
+                        }
+                        tracing::debug!("[{:?}] disconnected", addr);
+                        thread_connections.fetch_sub(1, Ordering::Relaxed);
+                    });
+                if let Err(err) = result {
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                    tracing::error!("unable to spawn connection thread: {:?}", err);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Serve a single connection. Commands are committed one by one, without
+/// write batching, so that an `Ack` only counts committed commands.
+fn handle_connection(
+    stream: TcpStream,
+    database_path: String,
+    auto_provision: Option<AutoProvision>,
+) -> Result<()> {
+    let mut instance = Kodama::instance(database_path)?;
+    if let Some(auto_provision) = auto_provision {
+        instance = instance.with_auto_provision(auto_provision);
+    }
+
+    let addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut processed = 0;
+    let mut failed = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let len = (&mut reader).take(MAX_MESSAGE_SIZE).read_line(&mut line)?;
+        if len == 0 {
+            return Ok(());
+        } else if !line.ends_with('\n') && len as u64 == MAX_MESSAGE_SIZE {
+            tracing::warn!(
+                "[{}] message exceeds {} bytes, closing",
+                addr,
+                MAX_MESSAGE_SIZE
+            );
+            return Ok(());
+        }
+
+        let message = line.trim();
+        if message.is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<StreamMessage>(message) {
+            Ok(StreamMessage::Command(command)) => {
+                processed += 1;
+                if let Err(err) = handle_command(&mut instance, command) {
+                    tracing::error!("[{}] error: {:?}", addr, err);
+                    failed += 1;
+                }
+            }
+            Ok(StreamMessage::Sync { sync }) => {
+                let ack = Ack {
+                    ack: sync,
+                    processed,
+                    failed,
+                };
+                let mut data = serde_json::to_vec(&ack)?;
+                data.push(b'\n');
+                writer.write_all(&data)?;
+            }
+            Err(err) => {
+                tracing::error!("[{}] invalid message: {:?}", addr, err);
+                processed += 1;
+                failed += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kodama_api::{Command, Labels, Record};
+
+    fn record(service_name: &str) -> String {
+        serde_json::to_string(&StreamMessage::Command(Command::Record(Record {
+            project_name: "shop".to_string(),
+            service_name: service_name.to_string(),
+            record_name: "checkout".to_string(),
+            group_by: "/".to_string(),
+            timestamp: None,
+            execution_time_us: 10,
+            error: 0,
+            labels: Default::default(),
+        })))
+        .unwrap()
+    }
+
+    #[test]
+    fn acknowledge_commands() {
+        let path = std::env::temp_dir().join(format!("kodama-tcp-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let database_path = path.to_str().unwrap().to_string();
+        let mut kodama = Kodama::instance(database_path.clone()).unwrap();
+        kodama.migrate().unwrap();
+        kodama.create_project("shop", "").unwrap();
+        kodama.create_service("shop", "api", "").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, database_path, None).unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut sync = |lines: &[&str], sync: u64| {
+            for line in lines {
+                writeln!(writer, "{}", line).unwrap();
+            }
+            writeln!(writer, r#"{{"sync": {}}}"#, sync).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let ack: Ack = serde_json::from_str(&line).unwrap();
+            (ack.ack, ack.processed, ack.failed)
+        };
+
+        assert_eq!(sync(&[&record("api"), &record("api")], 1), (1, 2, 0));
+        // an invalid line and an unknown service without auto provisioning
+        // both count as failed commands
+        assert_eq!(
+            sync(&[&record("api"), "{not json", "", &record("web")], 2),
+            (2, 5, 2)
+        );
+        writer.shutdown(std::net::Shutdown::Write).unwrap();
+        server.join().unwrap();
+
+        let entries = kodama
+            .record_entries(
+                "shop",
+                "api",
+                "checkout",
+                None,
+                None,
+                &[],
+                &Labels::new(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(entries[0].count, 3);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS retention_policies (
+    policy_id INTEGER PRIMARY KEY,
+    project_id INTEGER NOT NULL,
+    service_id INTEGER,
+    record_id INTEGER,
+    keep_us INTEGER NOT NULL,
+    FOREIGN KEY (project_id) REFERENCES projects(project_id),
+    FOREIGN KEY (service_id) REFERENCES services(service_id),
+    FOREIGN KEY (record_id) REFERENCES records(record_id)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_retention_policies_scope ON retention_policies (
+    project_id,
+    IFNULL(service_id, 0),
+    IFNULL(record_id, 0)
+);
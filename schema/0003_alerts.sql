@@ -0,0 +1,27 @@
+CREATE TABLE IF NOT EXISTS alert_rules (
+    alert_id INTEGER PRIMARY KEY,
+    service_id INTEGER NOT NULL,
+    alert_name TEXT NOT NULL,
+    target TEXT NOT NULL,
+    target_name TEXT NOT NULL,
+    aggregation TEXT NOT NULL,
+    window_us INTEGER NOT NULL,
+    comparison TEXT NOT NULL,
+    threshold REAL NOT NULL,
+    state TEXT NOT NULL DEFAULT 'ok',
+    value REAL,
+    evaluated_at INTEGER,
+    FOREIGN KEY (service_id) REFERENCES services(service_id),
+    UNIQUE (service_id, alert_name)
+);
+
+CREATE TABLE IF NOT EXISTS alert_history (
+    history_id INTEGER PRIMARY KEY,
+    alert_id INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    change TEXT NOT NULL,
+    value REAL NOT NULL,
+    FOREIGN KEY (alert_id) REFERENCES alert_rules(alert_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_alert_history_alert_id ON alert_history (alert_id, timestamp);
@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS notifiers (
+    notifier_id INTEGER PRIMARY KEY,
+    notifier_name TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    target TEXT NOT NULL,
+    UNIQUE (notifier_name)
+);
+
+CREATE TABLE IF NOT EXISTS alert_routes (
+    route_id INTEGER PRIMARY KEY,
+    alert_id INTEGER NOT NULL,
+    notifier_id INTEGER NOT NULL,
+    repeat_us INTEGER,
+    notified_history_id INTEGER NOT NULL DEFAULT 0,
+    notified_change TEXT,
+    notified_at INTEGER,
+    FOREIGN KEY (alert_id) REFERENCES alert_rules(alert_id),
+    FOREIGN KEY (notifier_id) REFERENCES notifiers(notifier_id),
+    UNIQUE (alert_id, notifier_id)
+);
@@ -0,0 +1,18 @@
+-- Concurrent writers could register the same record or metric twice. Keep
+-- the first registration and rename the others, so that their data stays
+-- reachable, before enforcing unique names.
+UPDATE records SET record_name = record_name || '#' || record_id
+WHERE record_id NOT IN (
+    SELECT MIN(record_id) FROM records GROUP BY service_id, record_name
+);
+
+UPDATE metrics SET metric_name = metric_name || '#' || metric_id
+WHERE metric_id NOT IN (
+    SELECT MIN(metric_id) FROM metrics GROUP BY service_id, metric_name
+);
+
+DROP INDEX IF EXISTS idx_records_service_id_record_name;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_records_service_id_record_name ON records (service_id, record_name);
+
+DROP INDEX IF EXISTS idx_metrics_service_id_metric_name;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_metrics_service_id_metric_name ON metrics (service_id, metric_name);
@@ -28,4 +28,6 @@ CREATE TABLE IF NOT EXISTS records (
     FOREIGN KEY (service_id) REFERENCES services(service_id)
 );
 
-CREATE INDEX IF NOT EXISTS idx_records_service_id_record_name ON records (service_id, record_name);
\ No newline at end of file
+CREATE INDEX IF NOT EXISTS idx_records_service_id_record_name ON records (service_id, record_name);
+
+CREATE INDEX IF NOT EXISTS idx_metrics_service_id_metric_name ON metrics (service_id, metric_name);